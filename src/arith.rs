@@ -0,0 +1,470 @@
+// Integer arithmetic for `$((...))`, `((...))` and substring offsets.
+// The expression is parsed into a small tree first so that `&&`, `||`
+// and `?:` can skip the side effects of the branch they do not take.
+
+use crate::shell::Shell;
+
+// Variables holding expressions are evaluated in turn, up to this depth
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Num(i64),
+    Name(String),
+    Op(&'static str),
+}
+
+// Longest first so that `<<=` wins over `<<` and `<`
+const OPERATORS: &[&str] = &[
+    "<<=", ">>=", "**", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+=", "-=", "*=", "/=", "%=",
+    "&=", "^=", "|=", "+", "-", "*", "/", "%", "<", ">", "&", "^", "|", "!", "~", "?", ":", "=", "(", ")", ",",
+];
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Num(i64),
+    Var(String),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    // `=` or a compound operator such as `+=`
+    Assign(&'static str, String, Box<Expr>),
+    // `++x` / `--x` (prefix) and `x++` / `x--`
+    Step { name: String, delta: i64, prefix: bool },
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Ternary(Box<Expr>, Box<Expr>, Box<Expr>),
+    Comma(Box<Expr>, Box<Expr>),
+}
+
+pub fn evaluate(shell: &mut Shell, expression: &str) -> Result<i64, String> {
+    evaluate_at(shell, expression, 0)
+}
+
+fn evaluate_at(shell: &mut Shell, expression: &str, depth: usize) -> Result<i64, String> {
+    if depth > MAX_DEPTH {
+        return Err(format!("{}: expression recursion level exceeded", expression.trim()));
+    }
+    let tokens = tokenize(expression)?;
+    // An empty expression counts as 0, as in `$(())`
+    if tokens.is_empty() {
+        return Ok(0);
+    }
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        source: expression.trim(),
+    };
+    let expr = parser.comma()?;
+    if let Some(token) = parser.tokens.get(parser.pos) {
+        return Err(parser.error_at(token));
+    }
+    Evaluator {
+        shell,
+        depth,
+        source: expression.trim(),
+    }
+    .eval(&expr)
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = expression.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_alphanumeric() || c == '_' {
+            let start = i;
+            // `#` only appears in numbers, as in 16#ff
+            while i < chars.len()
+                && (chars[i].is_ascii_alphanumeric() || chars[i] == '_' || (chars[i] == '#' && c.is_ascii_digit()))
+            {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            if c.is_ascii_digit() {
+                tokens.push(Token::Num(parse_number(&word).ok_or_else(|| {
+                    format!("{}: value too great for base (error token is \"{}\")", expression.trim(), word)
+                })?));
+            } else {
+                tokens.push(Token::Name(word));
+            }
+        } else {
+            let rest: String = chars[i..].iter().collect();
+            match OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+                Some(op) => {
+                    tokens.push(Token::Op(op));
+                    i += op.len();
+                }
+                None => {
+                    return Err(format!(
+                        "{}: syntax error: invalid arithmetic operator (error token is \"{}\")",
+                        expression.trim(),
+                        rest
+                    ))
+                }
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+// Decimal, 0x hex, leading-zero octal or base#digits
+fn parse_number(word: &str) -> Option<i64> {
+    let (base, digits) = if let Some((base, digits)) = word.split_once('#') {
+        (base.parse::<u32>().ok().filter(|base| (2..=64).contains(base))?, digits)
+    } else if let Some(hex) = word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+        (16, hex)
+    } else if word.len() > 1 && word.starts_with('0') {
+        (8, &word[1..])
+    } else {
+        (10, word)
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    let mut value: i64 = 0;
+    for c in digits.chars() {
+        let digit = match c {
+            '0'..='9' => c as u32 - '0' as u32,
+            // Up to base 36 letters are case-insensitive, then lowercase come before uppercase
+            'a'..='z' => c as u32 - 'a' as u32 + 10,
+            'A'..='Z' if base <= 36 => c as u32 - 'A' as u32 + 10,
+            'A'..='Z' => c as u32 - 'A' as u32 + 36,
+            '@' => 62,
+            '_' => 63,
+            _ => return None,
+        };
+        if digit >= base {
+            return None;
+        }
+        value = value.wrapping_mul(base as i64).wrapping_add(digit as i64);
+    }
+    Some(value)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    source: &'a str,
+}
+
+impl Parser<'_> {
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn eat(&mut self, op: &str) -> bool {
+        if self.peek_op() == Some(op) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn error_at(&self, token: &Token) -> String {
+        let rest = match token {
+            Token::Num(n) => n.to_string(),
+            Token::Name(name) => name.clone(),
+            Token::Op(op) => op.to_string(),
+        };
+        format!("{}: syntax error in expression (error token is \"{}\")", self.source, rest)
+    }
+
+    fn operand_expected(&self) -> String {
+        match self.tokens.get(self.pos) {
+            Some(token) => self.error_at(token),
+            None => format!("{}: syntax error: operand expected", self.source),
+        }
+    }
+
+    fn comma(&mut self) -> Result<Expr, String> {
+        let mut left = self.assignment()?;
+        while self.eat(",") {
+            let right = self.assignment()?;
+            left = Expr::Comma(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn assignment(&mut self) -> Result<Expr, String> {
+        if let (Some(Token::Name(name)), Some(Token::Op(op))) = (self.tokens.get(self.pos), self.tokens.get(self.pos + 1)) {
+            if matches!(*op, "=" | "*=" | "/=" | "%=" | "+=" | "-=" | "<<=" | ">>=" | "&=" | "^=" | "|=") {
+                let (name, op) = (name.clone(), *op);
+                self.pos += 2;
+                let value = self.assignment()?;
+                return Ok(Expr::Assign(op, name, Box::new(value)));
+            }
+        }
+        self.ternary()
+    }
+
+    fn ternary(&mut self) -> Result<Expr, String> {
+        let condition = self.binary(0)?;
+        if !self.eat("?") {
+            return Ok(condition);
+        }
+        let then = self.comma()?;
+        if !self.eat(":") {
+            return Err(self.operand_expected());
+        }
+        let otherwise = self.assignment()?;
+        Ok(Expr::Ternary(Box::new(condition), Box::new(then), Box::new(otherwise)))
+    }
+
+    // Binary operators from loosest to tightest binding, all left associative
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        const LEVELS: &[&[&str]] = &[
+            &["||"],
+            &["&&"],
+            &["|"],
+            &["^"],
+            &["&"],
+            &["==", "!="],
+            &["<", ">", "<=", ">="],
+            &["<<", ">>"],
+            &["+", "-"],
+            &["*", "/", "%"],
+        ];
+        if level == LEVELS.len() {
+            return self.power();
+        }
+        let mut left = self.binary(level + 1)?;
+        while let Some(op) = self.peek_op().filter(|op| LEVELS[level].contains(op)) {
+            self.pos += 1;
+            let right = self.binary(level + 1)?;
+            left = match op {
+                "&&" => Expr::And(Box::new(left), Box::new(right)),
+                "||" => Expr::Or(Box::new(left), Box::new(right)),
+                _ => Expr::Binary(op, Box::new(left), Box::new(right)),
+            };
+        }
+        Ok(left)
+    }
+
+    // `**` is right associative and binds tighter than the other binary operators
+    fn power(&mut self) -> Result<Expr, String> {
+        let base = self.unary()?;
+        if self.eat("**") {
+            let exponent = self.power()?;
+            return Ok(Expr::Binary("**", Box::new(base), Box::new(exponent)));
+        }
+        Ok(base)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.peek_op() {
+            Some(op @ ("++" | "--")) => {
+                self.pos += 1;
+                match self.tokens.get(self.pos) {
+                    Some(Token::Name(name)) => {
+                        let name = name.clone();
+                        self.pos += 1;
+                        Ok(Expr::Step {
+                            name,
+                            delta: if op == "++" { 1 } else { -1 },
+                            prefix: true,
+                        })
+                    }
+                    // Not a variable, so read it as two signs, e.g. `--5`
+                    _ => {
+                        let sign = if op == "++" { "+" } else { "-" };
+                        let operand = self.unary()?;
+                        Ok(Expr::Unary(sign, Box::new(Expr::Unary(sign, Box::new(operand)))))
+                    }
+                }
+            }
+            Some(op @ ("+" | "-" | "!" | "~")) => {
+                self.pos += 1;
+                let operand = self.unary()?;
+                Ok(Expr::Unary(op, Box::new(operand)))
+            }
+            _ => self.postfix(),
+        }
+    }
+
+    fn postfix(&mut self) -> Result<Expr, String> {
+        match self.tokens.get(self.pos).cloned() {
+            Some(Token::Num(n)) => {
+                self.pos += 1;
+                Ok(Expr::Num(n))
+            }
+            Some(Token::Name(name)) => {
+                self.pos += 1;
+                match self.peek_op() {
+                    Some(op @ ("++" | "--")) => {
+                        self.pos += 1;
+                        Ok(Expr::Step {
+                            name,
+                            delta: if op == "++" { 1 } else { -1 },
+                            prefix: false,
+                        })
+                    }
+                    _ => Ok(Expr::Var(name)),
+                }
+            }
+            Some(Token::Op("(")) => {
+                self.pos += 1;
+                let inner = self.comma()?;
+                if !self.eat(")") {
+                    return Err(self.operand_expected());
+                }
+                Ok(inner)
+            }
+            _ => Err(self.operand_expected()),
+        }
+    }
+}
+
+struct Evaluator<'a> {
+    shell: &'a mut Shell,
+    depth: usize,
+    source: &'a str,
+}
+
+impl Evaluator<'_> {
+    fn eval(&mut self, expr: &Expr) -> Result<i64, String> {
+        match expr {
+            Expr::Num(n) => Ok(*n),
+            Expr::Var(name) => self.variable(name),
+            Expr::Unary(op, operand) => {
+                let value = self.eval(operand)?;
+                Ok(match *op {
+                    "-" => value.wrapping_neg(),
+                    "!" => (value == 0) as i64,
+                    "~" => !value,
+                    _ => value,
+                })
+            }
+            Expr::Binary(op, left, right) => {
+                let left = self.eval(left)?;
+                let right = self.eval(right)?;
+                self.apply(op, left, right)
+            }
+            Expr::Assign(op, name, value) => {
+                let value = self.eval(value)?;
+                let value = match op.strip_suffix('=').filter(|op| !op.is_empty()) {
+                    Some(op) => {
+                        let current = self.variable(name)?;
+                        self.apply(op, current, value)?
+                    }
+                    None => value,
+                };
+                self.assign(name, value)
+            }
+            Expr::Step { name, delta, prefix } => {
+                let old = self.variable(name)?;
+                let new = self.assign(name, old.wrapping_add(*delta))?;
+                Ok(if *prefix { new } else { old })
+            }
+            Expr::And(left, right) => Ok((self.eval(left)? != 0 && self.eval(right)? != 0) as i64),
+            Expr::Or(left, right) => Ok((self.eval(left)? != 0 || self.eval(right)? != 0) as i64),
+            Expr::Ternary(condition, then, otherwise) => {
+                if self.eval(condition)? != 0 {
+                    self.eval(then)
+                } else {
+                    self.eval(otherwise)
+                }
+            }
+            Expr::Comma(left, right) => {
+                self.eval(left)?;
+                self.eval(right)
+            }
+        }
+    }
+
+    fn apply(&self, op: &str, left: i64, right: i64) -> Result<i64, String> {
+        Ok(match op {
+            "+" => left.wrapping_add(right),
+            "-" => left.wrapping_sub(right),
+            "*" => left.wrapping_mul(right),
+            "/" | "%" if right == 0 => return Err(format!("{}: division by 0", self.source)),
+            "/" => left.wrapping_div(right),
+            "%" => left.wrapping_rem(right),
+            "**" if right < 0 => return Err(format!("{}: exponent less than 0", self.source)),
+            "**" => left.wrapping_pow(right.min(u32::MAX as i64) as u32),
+            "<<" => left.wrapping_shl(right as u32),
+            ">>" => left.wrapping_shr(right as u32),
+            "&" => left & right,
+            "^" => left ^ right,
+            "|" => left | right,
+            "==" => (left == right) as i64,
+            "!=" => (left != right) as i64,
+            "<" => (left < right) as i64,
+            ">" => (left > right) as i64,
+            "<=" => (left <= right) as i64,
+            ">=" => (left >= right) as i64,
+            _ => return Err(format!("{}: syntax error in expression", self.source)),
+        })
+    }
+
+    // Unset or empty is 0, anything else is itself evaluated as an expression
+    fn variable(&mut self, name: &str) -> Result<i64, String> {
+        let value = self.shell.vars.get(name).unwrap_or_default().to_string();
+        if value.trim().is_empty() {
+            return Ok(0);
+        }
+        if let Ok(n) = value.trim().parse::<i64>() {
+            return Ok(n);
+        }
+        evaluate_at(self.shell, &value, self.depth + 1)
+    }
+
+    fn assign(&mut self, name: &str, value: i64) -> Result<i64, String> {
+        self.shell.vars.set(name, &value.to_string())?;
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expression: &str) -> Result<i64, String> {
+        evaluate(&mut Shell::new(), expression)
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(7));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+        assert_eq!(eval("2 + 3 << 1"), Ok(10));
+        assert_eq!(eval("1 || 0 && 0"), Ok(1));
+        assert_eq!(eval("-2 ** 2"), Ok(4));
+    }
+
+    #[test]
+    fn power_is_right_associative() {
+        assert_eq!(eval("2 ** 3 ** 2"), Ok(512));
+    }
+
+    #[test]
+    fn division_by_zero() {
+        assert!(eval("1 / 0").is_err());
+        assert!(eval("1 % 0").is_err());
+    }
+
+    #[test]
+    fn min_divided_by_minus_one_wraps() {
+        assert_eq!(eval("-9223372036854775807 - 1"), Ok(i64::MIN));
+        assert_eq!(eval("(-9223372036854775807 - 1) / -1"), Ok(i64::MIN));
+        assert_eq!(eval("(-9223372036854775807 - 1) % -1"), Ok(0));
+    }
+
+    #[test]
+    fn variables_and_assignment() {
+        let mut shell = Shell::new();
+        shell.vars.set("x", "5").unwrap();
+        shell.vars.set("e", "x * 2").unwrap();
+        assert_eq!(evaluate(&mut shell, "x += 2, x++, x"), Ok(8));
+        assert_eq!(shell.vars.get("x"), Some("8"));
+        assert_eq!(evaluate(&mut shell, "e + unset"), Ok(16));
+        assert_eq!(evaluate(&mut shell, "0 && (x = 100)"), Ok(0));
+        assert_eq!(shell.vars.get("x"), Some("8"));
+    }
+}
@@ -0,0 +1,148 @@
+// Syntax tree produced by the parser and walked by the executor
+
+use std::rc::Rc;
+
+/// A word as it appeared in the input, with quotes and escapes still in place.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Word(pub String);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedirectOp {
+    // >
+    Output,
+    // >>
+    Append,
+    // <
+    Input,
+    // <>
+    ReadWrite,
+    // >|
+    Clobber,
+    // >&fd, >&-
+    DupOutput,
+    // <&fd, <&-
+    DupInput,
+    // &>
+    OutputBoth,
+    // &>>
+    AppendBoth,
+    // <<WORD and <<-WORD, the target holds the body
+    HereDoc { expand: bool },
+    // <<<word
+    HereString,
+}
+
+// Redirections are applied in the order they are written
+#[derive(Debug, Clone, PartialEq)]
+pub struct Redirect {
+    // Explicit fd before the operator, e.g. `2>`
+    pub fd: Option<i32>,
+    pub op: RedirectOp,
+    pub target: Word,
+}
+
+// NAME=value, either on its own or as a prefix for one command
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assignment {
+    pub name: String,
+    pub value: Word,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimpleCommand {
+    pub assignments: Vec<Assignment>,
+    pub words: Vec<Word>,
+    pub redirects: Vec<Redirect>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompoundCommand {
+    // { list; }
+    BraceGroup(List),
+    // ( list ), run in a forked copy of the shell
+    Subshell(List),
+    // ((expression)), true when the value is not zero
+    Arithmetic(Word),
+    // if list; then list; [elif list; then list;]... [else list;] fi
+    If {
+        // Each condition with the body it guards, the `if` first and then every `elif`
+        branches: Vec<(List, List)>,
+        else_body: Option<List>,
+    },
+    // while list; do list; done, or `until` which loops while the condition fails
+    While {
+        until: bool,
+        condition: List,
+        body: List,
+    },
+    // for name [in words]; do list; done, over the positional parameters without `in`
+    For {
+        name: String,
+        words: Option<Vec<Word>>,
+        body: List,
+    },
+    // for ((init; test; step)); do list; done
+    ArithFor {
+        init: Word,
+        test: Word,
+        step: Word,
+        body: List,
+    },
+    // case word in pattern) list ;; ... esac
+    Case { word: Word, items: Vec<CaseItem> },
+}
+
+// pattern | pattern ...) list, followed by its terminator
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaseItem {
+    pub patterns: Vec<Word>,
+    pub body: List,
+    pub terminator: CaseTerminator,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaseTerminator {
+    // ;; stops after this item
+    Break,
+    // ;& runs the next item's list without testing it
+    FallThrough,
+    // ;;& goes on testing the items after this one
+    Continue,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Simple(SimpleCommand),
+    // Redirections after a compound command apply to all of it
+    Compound(CompoundCommand, Vec<Redirect>),
+    // name() body, shared with the shell's function table once defined
+    FunctionDef { name: String, body: Rc<Command> },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pipeline {
+    pub commands: Vec<Command>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Connector {
+    // &&
+    And,
+    // ||
+    Or,
+}
+
+// Pipelines joined by `&&` / `||`, evaluated left to right
+#[derive(Debug, Clone, PartialEq)]
+pub struct AndOr {
+    pub first: Pipeline,
+    pub rest: Vec<(Connector, Pipeline)>,
+    // Terminated by `&`, so the shell does not wait for it
+    pub background: bool,
+}
+
+// And-or lists separated by `;`, `&` or newlines
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct List {
+    pub items: Vec<AndOr>,
+}
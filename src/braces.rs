@@ -0,0 +1,200 @@
+// Brace expansion on raw word text, done before any other expansion:
+// `a{b,c}d` becomes `abd acd` and `{1..5..2}` becomes `1 3 5`.
+
+pub fn expand_braces(word: &str) -> Vec<String> {
+    let chars: Vec<char> = word.chars().collect();
+    let active = active_positions(&chars);
+
+    for open in (0..chars.len()).filter(|&i| active[i] && chars[i] == '{') {
+        let Some((close, commas)) = find_close(&chars, &active, open) else {
+            continue;
+        };
+        let prefix: String = chars[..open].iter().collect();
+        let suffix: String = chars[close + 1..].iter().collect();
+        let inner: String = chars[open + 1..close].iter().collect();
+
+        let alternatives = if commas.is_empty() {
+            match sequence(&inner) {
+                Some(items) => items,
+                // Not a list or a sequence, so this brace is just a character
+                None => continue,
+            }
+        } else {
+            let mut alternatives = Vec::new();
+            let mut start = open + 1;
+            for comma in commas.iter().copied().chain(std::iter::once(close)) {
+                alternatives.push(chars[start..comma].iter().collect());
+                start = comma + 1;
+            }
+            alternatives
+        };
+
+        // Nested braces and later braces in the suffix expand recursively
+        return alternatives
+            .iter()
+            .flat_map(|alternative| expand_braces(&format!("{}{}{}", prefix, alternative, suffix)))
+            .collect();
+    }
+    vec![word.to_string()]
+}
+
+// Characters that can take part in brace expansion: not quoted, escaped,
+// or inside `${...}`, `$(...)` or backquotes
+fn active_positions(chars: &[char]) -> Vec<bool> {
+    let mut active = vec![false; chars.len()];
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 2,
+            '\'' => i = skip_until(chars, i + 1, '\'') + 1,
+            '$' if chars.get(i + 1) == Some(&'\'') => {
+                i += 2;
+                while i < chars.len() && chars[i] != '\'' {
+                    i += if chars[i] == '\\' { 2 } else { 1 };
+                }
+                i += 1;
+            }
+            '"' => {
+                i += 1;
+                while i < chars.len() && chars[i] != '"' {
+                    i += if chars[i] == '\\' { 2 } else { 1 };
+                }
+                i += 1;
+            }
+            '`' => i = skip_until(chars, i + 1, '`') + 1,
+            '$' if matches!(chars.get(i + 1), Some('{' | '(')) => i = skip_nested(chars, i + 1),
+            _ => {
+                active[i] = true;
+                i += 1;
+            }
+        }
+    }
+    active
+}
+
+fn skip_until(chars: &[char], start: usize, end: char) -> usize {
+    (start..chars.len()).find(|&i| chars[i] == end).unwrap_or(chars.len())
+}
+
+// Past the bracket matching the one at `open`
+fn skip_nested(chars: &[char], open: usize) -> usize {
+    let (opening, closing) = if chars[open] == '{' { ('{', '}') } else { ('(', ')') };
+    let mut depth = 0;
+    let mut i = open;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            c if c == opening => depth += 1,
+            c if c == closing => {
+                depth -= 1;
+                if depth == 0 {
+                    return i + 1;
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    chars.len()
+}
+
+// The matching `}` and the commas at the top level between them
+fn find_close(chars: &[char], active: &[bool], open: usize) -> Option<(usize, Vec<usize>)> {
+    let mut depth = 0;
+    let mut commas = Vec::new();
+    for i in open + 1..chars.len() {
+        if !active[i] {
+            continue;
+        }
+        match chars[i] {
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some((i, commas)),
+            '}' => depth -= 1,
+            ',' if depth == 0 => commas.push(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+// `x..y` or `x..y..step` over integers or single characters
+fn sequence(inner: &str) -> Option<Vec<String>> {
+    let parts: Vec<&str> = inner.split("..").collect();
+    let (start, end, step) = match parts.as_slice() {
+        [start, end] => (*start, *end, 1),
+        [start, end, step] => (*start, *end, step.parse::<i64>().ok()?),
+        _ => return None,
+    };
+    let step = step.unsigned_abs().max(1) as i64;
+
+    if let (Ok(first), Ok(last)) = (start.parse::<i64>(), end.parse::<i64>()) {
+        // A leading zero on either end pads every number to the same width
+        let padded = |text: &str| text.trim_start_matches('-').len() > 1 && text.trim_start_matches('-').starts_with('0');
+        let width = if padded(start) || padded(end) { start.len().max(end.len()) } else { 0 };
+        return Some(
+            stepped(first, last, step)
+                .map(|n| {
+                    if n < 0 {
+                        format!("-{:0>width$}", -n, width = width.saturating_sub(1))
+                    } else {
+                        format!("{:0>width$}", n, width = width)
+                    }
+                })
+                .collect(),
+        );
+    }
+
+    let mut start_chars = start.chars();
+    let mut end_chars = end.chars();
+    match (start_chars.next(), start_chars.next(), end_chars.next(), end_chars.next()) {
+        (Some(first), None, Some(last), None) if first.is_ascii_alphabetic() && last.is_ascii_alphabetic() => Some(
+            stepped(first as i64, last as i64, step)
+                .filter_map(|c| char::from_u32(c as u32))
+                // The result is raw word text again, so punctuation between `Z` and `a` is escaped
+                .map(|c| if c.is_ascii_alphanumeric() { c.to_string() } else { format!("\\{}", c) })
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+// From `first` to `last` inclusive, counting down when `last` is smaller
+fn stepped(first: i64, last: i64, step: i64) -> impl Iterator<Item = i64> {
+    let count = (first - last).unsigned_abs() / step as u64;
+    let direction = if last < first { -step } else { step };
+    (0..=count as i64).map(move |i| first + i * direction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_padded_sequence() {
+        let expected: Vec<String> = (1..=10).map(|n| format!("{:02}", n)).collect();
+        assert_eq!(expand_braces("{01..10}"), expected);
+    }
+
+    #[test]
+    fn letter_sequence_with_step() {
+        assert_eq!(expand_braces("{a..e..2}"), ["a", "c", "e"]);
+    }
+
+    #[test]
+    fn lists_and_nesting() {
+        assert_eq!(expand_braces("a{b,c}d"), ["abd", "acd"]);
+        assert_eq!(expand_braces("{a,b}{1,2}"), ["a1", "a2", "b1", "b2"]);
+        assert_eq!(expand_braces("x{a,{b,c}}"), ["xa", "xb", "xc"]);
+        assert_eq!(expand_braces("{1..10..3}"), ["1", "4", "7", "10"]);
+        assert_eq!(expand_braces("{3..1}"), ["3", "2", "1"]);
+    }
+
+    #[test]
+    fn words_left_alone() {
+        assert_eq!(expand_braces("{a}"), ["{a}"]);
+        assert_eq!(expand_braces("{}"), ["{}"]);
+        assert_eq!(expand_braces("'{a,b}'"), ["'{a,b}'"]);
+        assert_eq!(expand_braces("\\{a,b}"), ["\\{a,b}"]);
+        assert_eq!(expand_braces("${a,b}"), ["${a,b}"]);
+    }
+}
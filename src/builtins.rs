@@ -1,41 +1,485 @@
 use std::env;
+use std::fs::OpenOptions;
+use std::io::Write;
 use std::path::Path;
-use crate::utils::print_or_write;
+use crate::glob::GlobOptions;
+use crate::shell::{Shell, Unwind};
+use crate::utils::shell_quote;
+use crate::variables::{is_valid_name, split_assignment};
+
+// Every command the shell handles itself
+pub const BUILTINS: &[&str] = &[
+    "echo", "exit", "logout", "type", "pwd", "history", "cd", "trap", "export", "unset", "readonly", "set",
+    "shift", "return", "shopt", "break", "continue", "read", ":",
+];
 
 pub fn cmd_echo(input: &[&str]) -> String {
     input.join(" ")
 }
 
-pub fn cmd_cd(args: &[&str]) {
+// cd dir, keeping PWD and OLDPWD up to date for `~+` and `~-`
+pub fn cmd_cd(shell: &mut Shell, args: &[&str]) -> i32 {
     if args.is_empty() {
-        return;
+        return 0;
     }
     let path = args[0];
-    let target_path = path.replace("~", &env::var("HOME").unwrap_or_default());
-    if !env::set_current_dir(target_path).is_ok() {
-        println!("cd: {}: No such file or directory", path);
+    let previous = env::current_dir().ok();
+    if env::set_current_dir(path).is_err() {
+        eprintln!("cd: {}: No such file or directory", path);
+        return 1;
+    }
+    if let Some(previous) = previous {
+        let _ = shell.vars.set("OLDPWD", &previous.to_string_lossy());
+    }
+    if let Ok(current) = env::current_dir() {
+        let _ = shell.vars.set("PWD", &current.to_string_lossy());
+    }
+    0
+}
+
+// exit [n] / logout [n], defaulting to the last status
+pub fn cmd_exit(shell: &mut Shell, name: &str, args: &[&str]) -> i32 {
+    let status = match args {
+        [] => shell.last_status,
+        [n] => match n.parse::<i64>() {
+            Ok(n) => (n & 0xff) as i32,
+            Err(_) => {
+                eprintln!("{}: {}: numeric argument required", name, n);
+                2
+            }
+        },
+        _ => {
+            eprintln!("{}: too many arguments", name);
+            return 1;
+        }
+    };
+    shell.shutdown(status)
+}
+
+// trap [command] EXIT, only the exit condition is supported
+pub fn cmd_trap(shell: &mut Shell, args: &[&str]) -> i32 {
+    match args {
+        [] => {
+            if let Some(command) = &shell.exit_trap {
+                println!("trap -- '{}' EXIT", command.replace('\'', "'\\''"));
+            }
+            0
+        }
+        [action, conditions @ ..] if !conditions.is_empty() => {
+            for condition in conditions {
+                if !matches!(*condition, "EXIT" | "0") {
+                    eprintln!("trap: {}: only EXIT traps are supported", condition);
+                    return 1;
+                }
+            }
+            shell.exit_trap = match *action {
+                "-" => None,
+                action => Some(action.to_string()),
+            };
+            0
+        }
+        _ => {
+            eprintln!("trap: usage: trap [action] EXIT");
+            2
+        }
+    }
+}
+
+// Split NAME[=value] for export/readonly, complaining about bad names
+fn parse_name_value<'a>(builtin: &str, arg: &'a str) -> Option<(&'a str, Option<&'a str>)> {
+    let (name, value) = match split_assignment(arg) {
+        Some((name, value)) => (name, Some(value)),
+        None => (arg, None),
+    };
+    if is_valid_name(name) {
+        Some((name, value))
+    } else {
+        eprintln!("{}: `{}': not a valid identifier", builtin, arg);
+        None
+    }
+}
+
+// export [-n] [-p] NAME[=value]...
+pub fn cmd_export(shell: &mut Shell, args: &[&str]) -> i32 {
+    let mut unexport = false;
+    let mut names = args;
+    while let Some(flag) = names.first().filter(|arg| arg.starts_with('-')) {
+        match *flag {
+            "-n" => unexport = true,
+            "-p" => {}
+            "--" => {
+                names = &names[1..];
+                break;
+            }
+            _ => {
+                eprintln!("export: {}: invalid option", flag);
+                eprintln!("export: usage: export [-n] [-p] [name[=value] ...]");
+                return 2;
+            }
+        }
+        names = &names[1..];
+    }
+
+    if names.is_empty() {
+        for (name, var) in shell.vars.sorted() {
+            if var.exported {
+                match &var.value {
+                    Some(value) => println!("export {}={}", name, shell_quote(value)),
+                    None => println!("export {}", name),
+                }
+            }
+        }
+        return 0;
+    }
+
+    let mut status = 0;
+    for arg in names {
+        let Some((name, value)) = parse_name_value("export", arg) else {
+            status = 1;
+            continue;
+        };
+        if let Some(value) = value {
+            if let Err(message) = shell.vars.set(name, value) {
+                eprintln!("export: {}", message);
+                status = 1;
+                continue;
+            }
+        }
+        shell.vars.set_exported(name, !unexport);
+    }
+    status
+}
+
+// readonly [-p] NAME[=value]...
+pub fn cmd_readonly(shell: &mut Shell, args: &[&str]) -> i32 {
+    let names: Vec<&str> = args.iter().copied().filter(|arg| *arg != "-p").collect();
+    if names.is_empty() {
+        for (name, var) in shell.vars.sorted() {
+            if var.readonly {
+                match &var.value {
+                    Some(value) => println!("readonly {}={}", name, shell_quote(value)),
+                    None => println!("readonly {}", name),
+                }
+            }
+        }
+        return 0;
+    }
+
+    let mut status = 0;
+    for arg in names {
+        let Some((name, value)) = parse_name_value("readonly", arg) else {
+            status = 1;
+            continue;
+        };
+        if let Some(value) = value {
+            if let Err(message) = shell.vars.set(name, value) {
+                eprintln!("readonly: {}", message);
+                status = 1;
+                continue;
+            }
+        }
+        shell.vars.set_readonly(name);
+    }
+    status
+}
+
+// unset [-v|-f] NAME...
+pub fn cmd_unset(shell: &mut Shell, args: &[&str]) -> i32 {
+    let mut functions = false;
+    let mut names = args;
+    while let Some(flag) = names.first().filter(|arg| arg.starts_with('-')) {
+        match *flag {
+            "-v" => functions = false,
+            "-f" => functions = true,
+            "--" => {
+                names = &names[1..];
+                break;
+            }
+            _ => {
+                eprintln!("unset: {}: invalid option", flag);
+                eprintln!("unset: usage: unset [-f] [-v] [name ...]");
+                return 2;
+            }
+        }
+        names = &names[1..];
+    }
+
+    let mut status = 0;
+    for name in names {
+        if functions {
+            shell.functions.remove(*name);
+            continue;
+        }
+        if !is_valid_name(name) {
+            eprintln!("unset: `{}': not a valid identifier", name);
+            status = 1;
+        } else if let Err(message) = shell.vars.unset(name) {
+            eprintln!("unset: {}", message);
+            status = 1;
+        }
+    }
+    status
+}
+
+// set with no arguments lists every variable, `set [--] args` replaces $1, $2, ...
+pub fn cmd_set(shell: &mut Shell, args: &[&str]) -> i32 {
+    let positional = match args {
+        [] => {
+            for (name, var) in shell.vars.sorted() {
+                if let Some(value) = &var.value {
+                    println!("{}={}", name, shell_quote(value));
+                }
+            }
+            return 0;
+        }
+        ["--", rest @ ..] => rest,
+        [flag, ..] if flag.starts_with('-') || flag.starts_with('+') => {
+            eprintln!("set: {}: invalid option", flag);
+            eprintln!("set: usage: set [--] [arg ...]");
+            return 2;
+        }
+        _ => args,
+    };
+    shell.positional = positional.iter().map(|arg| arg.to_string()).collect();
+    0
+}
+
+// shopt [-s|-u] [-p] [-q] [name...]
+pub fn cmd_shopt(shell: &mut Shell, args: &[&str]) -> i32 {
+    let mut setting: Option<bool> = None;
+    let mut reusable = false;
+    let mut quiet = false;
+    let mut names = args;
+    while let Some(flag) = names.first().filter(|arg| arg.starts_with('-')) {
+        match *flag {
+            "-s" => setting = Some(true),
+            "-u" => setting = Some(false),
+            "-p" => reusable = true,
+            "-q" => quiet = true,
+            _ => {
+                eprintln!("shopt: {}: invalid option", flag);
+                eprintln!("shopt: usage: shopt [-pqsu] [optname ...]");
+                return 2;
+            }
+        }
+        names = &names[1..];
+    }
+
+    let listing = names.is_empty();
+    let names = if listing { GlobOptions::NAMES } else { names };
+    let mut status = 0;
+    for name in names {
+        let Some(value) = shell.glob_options.get_mut(name) else {
+            eprintln!("shopt: {}: invalid shell option name", name);
+            status = 1;
+            continue;
+        };
+        if let (Some(on), false) = (setting, listing) {
+            *value = on;
+            continue;
+        }
+        let on = *value;
+        // `shopt -s` / `shopt -u` alone list the options that are on / off
+        if setting.is_some_and(|setting| setting != on) {
+            continue;
+        }
+        // Asking about named options reports whether they are all on
+        if !on && !listing {
+            status = 1;
+        }
+        if quiet {
+            continue;
+        }
+        if reusable {
+            println!("shopt {} {}", if on { "-s" } else { "-u" }, name);
+        } else {
+            println!("{:<15}\t{}", name, if on { "on" } else { "off" });
+        }
+    }
+    status
+}
+
+// shift [n] drops the first n positional parameters
+pub fn cmd_shift(shell: &mut Shell, args: &[&str]) -> i32 {
+    let count = match args {
+        [] => 1,
+        [n] => match n.parse::<usize>() {
+            Ok(n) => n,
+            Err(_) => {
+                eprintln!("shift: {}: numeric argument required", n);
+                return 1;
+            }
+        },
+        _ => {
+            eprintln!("shift: too many arguments");
+            return 1;
+        }
+    };
+    if count > shell.positional.len() {
+        eprintln!("shift: {}: shift count out of range", count);
+        return 1;
+    }
+    shell.positional.drain(..count);
+    0
+}
+
+// return [n] leaves the innermost function, defaulting to the last status
+pub fn cmd_return(shell: &mut Shell, args: &[&str]) -> i32 {
+    if shell.function_depth == 0 {
+        eprintln!("return: can only `return' from a function");
+        return 1;
+    }
+    let status = match args {
+        [] => shell.last_status,
+        [n] => match n.parse::<i64>() {
+            Ok(n) => (n & 0xff) as i32,
+            Err(_) => {
+                eprintln!("return: {}: numeric argument required", n);
+                2
+            }
+        },
+        _ => {
+            eprintln!("return: too many arguments");
+            return 1;
+        }
+    };
+    shell.unwind = Some(Unwind::Return);
+    status
+}
+
+// break [n] / continue [n] for the n innermost loops, counting only those in the current function
+pub fn cmd_loop_control(shell: &mut Shell, name: &str, args: &[&str]) -> i32 {
+    if shell.loop_depth == 0 {
+        eprintln!("{}: only meaningful in a `for', `while', or `until' loop", name);
+        return 0;
+    }
+    // A bad count still leaves every loop, as bash does
+    let count = match args {
+        [] => 1,
+        [n] => match n.parse::<i64>() {
+            Ok(count) if count >= 1 => count as usize,
+            Ok(_) => {
+                eprintln!("{}: {}: loop count out of range", name, n);
+                shell.unwind = Some(Unwind::Break(shell.loop_depth));
+                return 1;
+            }
+            Err(_) => {
+                eprintln!("{}: {}: numeric argument required", name, n);
+                shell.unwind = Some(Unwind::Break(shell.loop_depth));
+                return 1;
+            }
+        },
+        _ => {
+            eprintln!("{}: too many arguments", name);
+            return 1;
+        }
+    };
+    // More loops than there are just means all of them
+    let count = count.min(shell.loop_depth);
+    shell.unwind = Some(if name == "break" { Unwind::Break(count) } else { Unwind::Continue(count) });
+    0
+}
+
+// read [-r] [name...] takes one line of stdin, splitting it on IFS into the
+// names with the last one getting the rest, or all of it into REPLY.
+// Fails at end of input, which is what ends `while read line`.
+pub fn cmd_read(shell: &mut Shell, args: &[&str]) -> i32 {
+    let (raw, names) = match args {
+        ["-r", names @ ..] => (true, names),
+        _ => (false, args),
+    };
+    if let Some(name) = names.iter().find(|name| !is_valid_name(name)) {
+        eprintln!("read: `{}': not a valid identifier", name);
+        return 1;
+    }
+
+    // A byte at a time, so nothing after the newline is taken from a shared fd
+    let mut bytes = Vec::new();
+    let mut complete = false;
+    let mut escaped = false;
+    loop {
+        let mut byte = 0u8;
+        if unsafe { libc::read(0, &mut byte as *mut u8 as *mut libc::c_void, 1) } != 1 {
+            break;
+        }
+        // Without -r a backslash keeps the next character and joins lines
+        if escaped {
+            escaped = false;
+            if byte != b'\n' {
+                bytes.push(byte);
+            }
+            continue;
+        }
+        match byte {
+            b'\\' if !raw => escaped = true,
+            b'\n' => {
+                complete = true;
+                break;
+            }
+            _ => bytes.push(byte),
+        }
+    }
+    let line = String::from_utf8_lossy(&bytes).into_owned();
+
+    let ifs = shell.vars.get("IFS").unwrap_or(" \t\n").to_string();
+    let values = match names {
+        [] => vec![("REPLY", line)],
+        _ => {
+            let blank = |c: char| matches!(c, ' ' | '\t' | '\n') && ifs.contains(c);
+            let mut rest = line.trim_start_matches(blank);
+            let mut values = Vec::new();
+            for (i, name) in names.iter().enumerate() {
+                if i == names.len() - 1 {
+                    values.push((*name, rest.trim_end_matches(blank).to_string()));
+                    break;
+                }
+                let end = rest.find(|c: char| ifs.contains(c)).unwrap_or(rest.len());
+                values.push((*name, rest[..end].to_string()));
+                // The separator is IFS whitespace around at most one other IFS character
+                rest = rest[end..].trim_start_matches(blank);
+                if let Some(c) = rest.chars().next().filter(|&c| ifs.contains(c)) {
+                    rest = rest[c.len_utf8()..].trim_start_matches(blank);
+                }
+            }
+            values
+        }
+    };
+    for (name, value) in values {
+        if let Err(message) = shell.vars.set(name, &value) {
+            eprintln!("read: {}", message);
+            return 1;
+        }
     }
+    if complete { 0 } else { 1 }
 }
 
-pub fn cmd_type(args: &[&str], output_file: Option<&(String, bool)>) {
+pub fn cmd_type(shell: &Shell, args: &[&str]) -> i32 {
     if args.is_empty() {
-        return;
+        return 0;
+    }
+    if shell.functions.contains_key(args[0]) {
+        println!("{} is a function", args[0]);
+        return 0;
+    }
+    let output = execute_type_builtin(args, shell.vars.get("PATH"));
+    println!("{}", output);
+    if output.ends_with(": not found") {
+        1
+    } else {
+        0
     }
-    let output = execute_type_builtin(args);
-    print_or_write(output_file, &output);
 }
 
-pub fn execute_type_builtin(args: &[&str]) -> String {
+pub fn execute_type_builtin(args: &[&str], path_var: Option<&str>) -> String {
     if args.is_empty() {
         return String::new();
     }
     
-    let builtins = vec!["echo", "exit", "type", "pwd","history", "cd"];
     let command = args[0];
 
-    if builtins.contains(&command) {
+    if BUILTINS.contains(&command) {
         format!("{} is a shell builtin", command)
-    } else if let Ok(path_var) = env::var("PATH") {
+    } else if let Some(path_var) = path_var {
         for dir in path_var.split(':') {
             let full_path = Path::new(dir).join(command);
             if full_path.exists() {
@@ -48,33 +492,7 @@ pub fn execute_type_builtin(args: &[&str]) -> String {
     }
 }
 
-pub fn execute_builtin(cmd: &[&str]) -> String {
-    match cmd[0] {
-        "echo" => {
-            if cmd.len() > 1 {
-                cmd_echo(&cmd[1..])
-            } else {
-                String::new()
-            }
-        }
-        "pwd" => {
-            env::current_dir()
-                .map(|path| path.display().to_string())
-                .unwrap_or_else(|_| String::new())
-        }
-        "type" => {
-            if cmd.len() > 1 {
-                execute_type_builtin(&cmd[1..])
-            } else {
-                String::new()
-            }
-        }
-        _ => String::new(),
-    }
-}
-
-
-pub fn cmd_history(history_entries: &[String], args: &[&str], output_file: Option<&(String, bool)>) {
+pub fn cmd_history(history_entries: &[String], args: &[&str]) {
     let entries_to_show = if args.is_empty() {
         // Show all history if no limit specified
         history_entries
@@ -111,7 +529,67 @@ pub fn cmd_history(history_entries: &[String], args: &[&str], output_file: Optio
     };
 
     for (i, entry) in entries_to_show.iter().enumerate() {
-        let line = format!("    {}  {}", start_index + i, entry);
-        print_or_write(output_file, &line);
+        println!("    {}  {}", start_index + i, entry);
+    }
+}
+
+// history -r/-w/-a <file>
+pub fn cmd_history_file(shell: &mut Shell, option: &str, file_path: &str) -> i32 {
+    match option {
+        "-r" => {
+            // Read history from file
+            match std::fs::read_to_string(file_path) {
+                Ok(contents) => {
+                    shell.read_history(&contents);
+                    shell.last_written_pos = shell.history_entries().len();
+                    0
+                }
+                Err(e) => {
+                    eprintln!("history: {}: {}", file_path, e);
+                    1
+                }
+            }
+        }
+        "-w" => {
+            // Write all history to file
+            let history = shell.history_entries();
+            match std::fs::write(file_path, history.join("\n") + "\n") {
+                Ok(_) => {
+                    shell.last_written_pos = history.len();
+                    0
+                }
+                Err(e) => {
+                    eprintln!("history: {}: {}", file_path, e);
+                    1
+                }
+            }
+        }
+        "-a" => {
+            // Append new history to file
+            let history = shell.history_entries();
+
+            // Get commands that haven't been written yet (only from current session)
+            let start_pos = shell.last_written_pos;
+            if start_pos >= history.len() {
+                return 0;
+            }
+            match OpenOptions::new().create(true).append(true).open(file_path) {
+                Ok(mut file) => {
+                    for command in &history[start_pos..] {
+                        if let Err(e) = writeln!(file, "{}", command) {
+                            eprintln!("history: {}: {}", file_path, e);
+                            return 1;
+                        }
+                    }
+                    shell.last_written_pos = history.len();
+                    0
+                }
+                Err(e) => {
+                    eprintln!("history: {}: {}", file_path, e);
+                    1
+                }
+            }
+        }
+        _ => 2,
     }
-}
\ No newline at end of file
+}
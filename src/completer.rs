@@ -1,10 +1,11 @@
-use std::env;
 use std::cell::RefCell;
 use rustyline::completion::{Completer, Pair};
 use rustyline::error::ReadlineError;
 use rustyline::{Context, Helper};
 use rustyline::highlight::Highlighter;
-use rustyline::validate::Validator;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use crate::builtins::BUILTINS;
+use crate::parser::needs_more_input;
 use crate::utils::is_executable;
 use std::io::Write;
 
@@ -12,6 +13,8 @@ pub struct MyCompleter {
     last_line: RefCell<String>,
     last_pos: RefCell<usize>,
     tab_count: RefCell<usize>,
+    // The shell's PATH, which can differ from the process environment
+    path: String,
 }
 
 impl MyCompleter {
@@ -20,8 +23,13 @@ impl MyCompleter {
             last_line: RefCell::new(String::new()),
             last_pos: RefCell::new(0),
             tab_count: RefCell::new(0),
+            path: String::new(),
         }
     }
+
+    pub fn set_path(&mut self, path: &str) {
+        self.path = path.to_string();
+    }
 }
 
 impl Completer for MyCompleter {
@@ -50,8 +58,7 @@ impl Completer for MyCompleter {
 
         // Complete built-in commands only if we're at the beginning
         if tokens.len() <= 1 {
-            let builtins = vec!["echo", "exit", "type", "pwd", "cd"];
-            for cmd in builtins {
+            for cmd in BUILTINS {
                 if cmd.starts_with(last_token) {
                     candidates.push(Pair {
                         display: format!("{} ", cmd),
@@ -63,16 +70,16 @@ impl Completer for MyCompleter {
 
         // Path execution - find all matching executables
         let mut executable_matches = Vec::new();
-        if let Ok(path_env) = env::var("PATH") {
-            for dir in path_env.split(':') {
-                if let Ok(entries) = std::fs::read_dir(dir) {
-                    for entry in entries.flatten() {
-                        if let Ok(file_name) = entry.file_name().into_string() {
-                            if file_name.starts_with(last_token) && entry.path().is_file() && is_executable(&entry.path()) {
-                                if !executable_matches.contains(&file_name) {
-                                    executable_matches.push(file_name.clone());
-                                }
-                            }
+        for dir in self.path.split(':') {
+            if let Ok(entries) = std::fs::read_dir(dir) {
+                for entry in entries.flatten() {
+                    if let Ok(file_name) = entry.file_name().into_string() {
+                        if file_name.starts_with(last_token)
+                            && entry.path().is_file()
+                            && is_executable(&entry.path())
+                            && !executable_matches.contains(&file_name)
+                        {
+                            executable_matches.push(file_name.clone());
                         }
                     }
                 }
@@ -135,7 +142,17 @@ impl rustyline::hint::Hinter for MyCompleter {
     }
 }
 impl Highlighter for MyCompleter {}
-impl Validator for MyCompleter {}
+// Enter on an unfinished command adds a line instead of running it, so
+// a multi-line command comes back from readline in one piece
+impl Validator for MyCompleter {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        if needs_more_input(ctx.input()) {
+            Ok(ValidationResult::Incomplete)
+        } else {
+            Ok(ValidationResult::Valid(None))
+        }
+    }
+}
 
 fn find_longest_common_prefix(strings: &[String]) -> String {
     if strings.is_empty() {
@@ -1,189 +1,794 @@
-use std::process::{Command, Stdio, Child};
-use std::fs::{File, OpenOptions};
-use std::path::Path;
-use std::io::Write;
+use std::process::{Command, ExitStatus};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::process::ExitStatusExt;
 use std::env;
-use crate::builtins::execute_builtin;
-
-pub fn parse_pipeline(command_str: &str) -> Option<Vec<Vec<String>>> {
-    if command_str.contains(" | ") {
-        let parts: Vec<&str> = command_str.split(" | ").collect();
-        let mut commands = Vec::new();
-        
-        for part in parts {
-            if let Some(args) = shlex::split(part.trim()) {
-                if !args.is_empty() {
-                    commands.push(args);
+use crate::arith::evaluate;
+use crate::ast::{
+    AndOr, CaseItem, CaseTerminator, Command as ShellCommand, CompoundCommand, Connector, List, Pipeline, SimpleCommand,
+    Word,
+};
+use crate::builtins::{
+    cmd_cd, cmd_echo, cmd_exit, cmd_export, cmd_history, cmd_history_file, cmd_loop_control, cmd_read, cmd_readonly,
+    cmd_return, cmd_set, cmd_shift, cmd_shopt, cmd_trap, cmd_type, cmd_unset,
+};
+use crate::expansion::{expand_arithmetic, expand_assignment, expand_pattern, expand_word, expand_words};
+use crate::parser::parse;
+use crate::pattern::Pattern;
+use crate::redirection::apply_redirections;
+use crate::shell::{Shell, Unwind};
+use crate::signals;
+use crate::utils::{io_error_message, is_executable};
+use crate::variables::is_valid_name;
+
+pub fn execute_list(shell: &mut Shell, list: &List) -> i32 {
+    for and_or in &list.items {
+        shell.last_status = if and_or.background {
+            run_background(shell, and_or)
+        } else {
+            execute_and_or(shell, and_or)
+        };
+        // Ctrl-C or `return` abandons the rest of the list
+        if signals::interrupted() || shell.unwind.is_some() {
+            break;
+        }
+    }
+    shell.last_status
+}
+
+pub fn execute_and_or(shell: &mut Shell, and_or: &AndOr) -> i32 {
+    let mut status = execute_pipeline(shell, &and_or.first);
+    for (connector, pipeline) in &and_or.rest {
+        if signals::interrupted() || shell.unwind.is_some() {
+            break;
+        }
+        // `&&` runs on success, `||` on failure; otherwise the status carries over
+        let run = match connector {
+            Connector::And => status == 0,
+            Connector::Or => status != 0,
+        };
+        if run {
+            status = execute_pipeline(shell, pipeline);
+        }
+    }
+    status
+}
+
+pub fn execute_pipeline(shell: &mut Shell, pipeline: &Pipeline) -> i32 {
+    if pipeline.commands.len() == 1 {
+        // Single command, execute normally
+        return execute_command(shell, &pipeline.commands[0]);
+    }
+
+    let mut pids = Vec::new();
+    // Process group shared by every stage, led by the first one
+    let mut pgid = 0;
+    // Read end of the previous stage's pipe
+    let mut input: Option<OwnedFd> = None;
+
+    for (i, command) in pipeline.commands.iter().enumerate() {
+        let is_last = i == pipeline.commands.len() - 1;
+        let (next_input, output) = if is_last {
+            (None, None)
+        } else {
+            match make_pipe() {
+                Ok((read_end, write_end)) => (Some(read_end), Some(write_end)),
+                Err(e) => {
+                    eprintln!("pipe: {}", e);
+                    break;
+                }
+            }
+        };
+
+        match fork_process(shell, pgid, true) {
+            Ok(0) => {
+                // Child: wire up the pipe ends, then the stage's own redirections
+                // are applied on top by execute_command
+                unsafe {
+                    if let Some(fd) = &input {
+                        libc::dup2(fd.as_raw_fd(), 0);
+                    }
+                    if let Some(fd) = &output {
+                        libc::dup2(fd.as_raw_fd(), 1);
+                    }
+                }
+                drop((input, output, next_input));
+                let status = execute_command(shell, command);
+                shell.shutdown(status);
+            }
+            Ok(pid) => {
+                if pgid == 0 {
+                    pgid = pid;
                 }
-            } else {
-                return None; // Parse error
+                pids.push(pid);
+            }
+            Err(e) => {
+                eprintln!("fork: {}", e);
+                break;
             }
         }
-        
-        if commands.len() >= 2 {
-            return Some(commands);
+        // The parent keeps only the read end the next stage needs
+        input = next_input;
+    }
+    drop(input);
+
+    // Wait for all processes to complete, the last stage decides the status
+    let mut last_status = 1;
+    for pid in pids {
+        last_status = wait_pid(pid);
+    }
+    signals::reclaim_terminal();
+    last_status
+}
+
+fn make_pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    unsafe { Ok((OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1]))) }
+}
+
+// Fork a subshell that joins job `pgid`, returning 0 in the child
+fn fork_process(shell: &mut Shell, pgid: i32, foreground: bool) -> io::Result<i32> {
+    let _ = io::stdout().flush();
+    let _ = io::stderr().flush();
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            signals::enter_child(pgid, foreground);
+            shell.enter_subshell();
+            Ok(0)
+        }
+        pid if foreground => {
+            signals::foreground_job(pid as u32, pgid);
+            Ok(pid)
+        }
+        pid => {
+            signals::background_job(pid as u32);
+            Ok(pid)
         }
     }
-    None
 }
 
-pub fn execute_pipeline(commands: &[Vec<String>]) {
-    if commands.is_empty() {
-        return;
+// `cmd &`: run in a forked subshell and carry on without waiting
+fn run_background(shell: &mut Shell, and_or: &AndOr) -> i32 {
+    match fork_process(shell, 0, false) {
+        Ok(0) => {
+            let status = execute_and_or(shell, and_or);
+            shell.shutdown(status);
+        }
+        Ok(pid) => {
+            shell.last_background_pid = Some(pid);
+            0
+        }
+        Err(e) => {
+            eprintln!("fork: {}", e);
+            1
+        }
     }
-    
-    if commands.len() == 1 {
-        // Single command, execute normally
-        let cmd_args: Vec<&str> = commands[0].iter().map(|s| s.as_str()).collect();
-        execute_single_command(&cmd_args);
-        return;
-    }
-    
-    let builtins = vec!["echo", "exit", "type", "pwd", "cd"];
-    let mut processes: Vec<Child> = Vec::new();
-    let mut previous_output: Option<String> = None;
-    
-    for (i, command) in commands.iter().enumerate() {
-        let cmd_args: Vec<&str> = command.iter().map(|s| s.as_str()).collect();
-        let is_builtin = builtins.contains(&cmd_args[0]);
-        let is_first = i == 0;
-        let is_last = i == commands.len() - 1;
-        
-        if is_builtin {
-            // Handle built-in command
-            let output = execute_builtin(&cmd_args);
-            
-            if is_last {
-                // Last command, print to stdout
-                if !output.is_empty() {
-                    println!("{}", output);
+}
+
+// $(...): run `source` in a subshell and collect what it writes to stdout
+pub fn capture_output(shell: &mut Shell, source: &str) -> Result<String, String> {
+    let list = parse(source).map_err(|e| e.to_string())?;
+    let (read_end, write_end) = make_pipe().map_err(|e| format!("pipe: {}", e))?;
+    let pid = match fork_process(shell, 0, true) {
+        Ok(0) => {
+            unsafe {
+                libc::dup2(write_end.as_raw_fd(), 1);
+            }
+            drop((read_end, write_end));
+            let status = execute_list(shell, &list);
+            shell.shutdown(status);
+        }
+        Ok(pid) => pid,
+        Err(e) => return Err(format!("fork: {}", e)),
+    };
+    // Reading stops at EOF, once every copy of the write end is closed
+    drop(write_end);
+    let mut output = Vec::new();
+    let _ = File::from(read_end).read_to_end(&mut output);
+    let status = wait_pid(pid);
+    signals::reclaim_terminal();
+    shell.last_status = status;
+    shell.substitution_status = Some(status);
+
+    let mut text = String::from_utf8_lossy(&output).into_owned();
+    text.truncate(text.trim_end_matches('\n').len());
+    Ok(text)
+}
+
+// Collect background jobs that have finished so they do not linger as zombies
+pub fn reap_background() {
+    let mut raw_status = 0;
+    while unsafe { libc::waitpid(-1, &mut raw_status, libc::WNOHANG) } > 0 {}
+}
+
+pub fn execute_command(shell: &mut Shell, command: &ShellCommand) -> i32 {
+    match command {
+        ShellCommand::Simple(simple) => execute_simple_command(shell, simple),
+        ShellCommand::Compound(compound, redirects) => {
+            // Restores the shell's fds when it goes out of scope
+            let _saved_fds = match apply_redirections(shell, redirects) {
+                Ok(saved_fds) => saved_fds,
+                Err(message) => {
+                    eprintln!("{}", message);
+                    return 1;
                 }
-            } else {
-                // Store output for next command
-                previous_output = Some(output);
+            };
+            execute_compound(shell, compound)
+        }
+        ShellCommand::FunctionDef { name, body } => {
+            shell.functions.insert(name.clone(), body.clone());
+            0
+        }
+    }
+}
+
+fn execute_compound(shell: &mut Shell, compound: &CompoundCommand) -> i32 {
+    match compound {
+        CompoundCommand::BraceGroup(list) => execute_list(shell, list),
+        CompoundCommand::Subshell(list) => match fork_process(shell, 0, true) {
+            Ok(0) => {
+                let status = execute_list(shell, list);
+                shell.shutdown(status);
             }
-        } else {
-            // Handle external command
-            let mut cmd = Command::new(&cmd_args[0]);
-            cmd.args(&cmd_args[1..]);
-            
-            // Set up stdin
-            if is_first {
-                cmd.stdin(Stdio::inherit());
-            } else if let Some(ref prev_output) = previous_output {
-                // Previous command was a built-in, pipe its output
-                cmd.stdin(Stdio::piped());
-            } else if !processes.is_empty() {
-                // Previous command was external, use its stdout
-                if let Some(stdout) = processes.last_mut().unwrap().stdout.take() {
-                    cmd.stdin(Stdio::from(stdout));
+            Ok(pid) => {
+                let status = wait_pid(pid);
+                signals::reclaim_terminal();
+                status
+            }
+            Err(e) => {
+                eprintln!("fork: {}", e);
+                1
+            }
+        },
+        CompoundCommand::Arithmetic(expression) => {
+            match evaluate_word(shell, expression) {
+                Ok(value) => (value == 0) as i32,
+                Err(message) => {
+                    eprintln!("{}", message);
+                    1
                 }
             }
-            
-            // Set up stdout
-            if is_last {
-                cmd.stdout(Stdio::inherit());
-            } else {
-                cmd.stdout(Stdio::piped());
-            }
-            
-            let mut process = cmd.spawn().expect("Failed to start command");
-            
-            // If previous command was built-in, write its output to this process's stdin
-            if let Some(ref prev_output) = previous_output {
-                if let Some(mut stdin) = process.stdin.take() {
-                    let _ = stdin.write_all(prev_output.as_bytes());
-                    if !prev_output.ends_with('\n') {
-                        let _ = stdin.write_all(b"\n");
-                    }
-                    drop(stdin); // Close stdin
+        }
+        CompoundCommand::While { until, condition, body } => execute_while(shell, *until, condition, body),
+        CompoundCommand::For { name, words, body } => execute_for(shell, name, words.as_deref(), body),
+        CompoundCommand::ArithFor { init, test, step, body } => {
+            match execute_arith_for(shell, init, test, step, body) {
+                Ok(status) => status,
+                Err(message) => {
+                    eprintln!("{}", message);
+                    1
+                }
+            }
+        }
+        CompoundCommand::Case { word, items } => match execute_case(shell, word, items) {
+            Ok(status) => status,
+            Err(message) => {
+                eprintln!("{}", message);
+                1
+            }
+        },
+        CompoundCommand::If { branches, else_body } => {
+            for (condition, body) in branches {
+                let status = execute_list(shell, condition);
+                if signals::interrupted() || shell.unwind.is_some() {
+                    return status;
                 }
-                previous_output = None; // Clear it
+                if status == 0 {
+                    return execute_list(shell, body);
+                }
+            }
+            // With no branch taken and no else the status is 0
+            match else_body {
+                Some(body) => execute_list(shell, body),
+                None => 0,
+            }
+        }
+    }
+}
+
+fn execute_while(shell: &mut Shell, until: bool, condition: &List, body: &List) -> i32 {
+    shell.loop_depth += 1;
+    let mut status = 0;
+    loop {
+        let tested = execute_list(shell, condition);
+        if shell.unwind.is_some() || signals::interrupted() {
+            if end_of_pass(shell) {
+                break;
+            }
+            continue;
+        }
+        if (tested == 0) == until {
+            break;
+        }
+        status = execute_list(shell, body);
+        if end_of_pass(shell) {
+            break;
+        }
+    }
+    shell.loop_depth -= 1;
+    status
+}
+
+// Patterns match like pathname expansion but against the word, never the filesystem.
+// The status is that of the last list run, or 0 when nothing matched.
+fn execute_case(shell: &mut Shell, word: &Word, items: &[CaseItem]) -> Result<i32, String> {
+    let subject = expand_word(shell, word).map_err(|error| error.into_message(shell))?;
+    let mut status = 0;
+    // Set by `;&`, which runs the next list without testing its patterns
+    let mut fall_through = false;
+    for item in items {
+        if !fall_through && !case_matches(shell, &item.patterns, &subject)? {
+            continue;
+        }
+        status = if item.body.items.is_empty() { 0 } else { execute_list(shell, &item.body) };
+        if signals::interrupted() || shell.unwind.is_some() {
+            break;
+        }
+        match item.terminator {
+            CaseTerminator::Break => break,
+            CaseTerminator::FallThrough => fall_through = true,
+            CaseTerminator::Continue => fall_through = false,
+        }
+    }
+    Ok(status)
+}
+
+fn case_matches(shell: &mut Shell, patterns: &[Word], subject: &str) -> Result<bool, String> {
+    for pattern in patterns {
+        let pattern = expand_pattern(shell, pattern).map_err(|error| error.into_message(shell))?;
+        if Pattern::new(&pattern).matches(subject) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// The words after `in` get brace, glob and every other expansion
+fn execute_for(shell: &mut Shell, name: &str, words: Option<&[Word]>, body: &List) -> i32 {
+    if !is_valid_name(name) {
+        eprintln!("`{}': not a valid identifier", name);
+        return 1;
+    }
+    let items = match words {
+        Some(words) => match expand_words(shell, words) {
+            Ok(items) => items,
+            Err(error) => {
+                eprintln!("{}", error.into_message(shell));
+                return 1;
+            }
+        },
+        None => shell.positional.clone(),
+    };
+    shell.loop_depth += 1;
+    let mut status = 0;
+    for item in items {
+        if let Err(message) = shell.vars.set(name, &item) {
+            eprintln!("{}", message);
+            status = 1;
+            break;
+        }
+        status = execute_list(shell, body);
+        if end_of_pass(shell) {
+            break;
+        }
+    }
+    shell.loop_depth -= 1;
+    status
+}
+
+// An empty test counts as true, so `for ((;;))` runs until `break`
+fn execute_arith_for(shell: &mut Shell, init: &Word, test: &Word, step: &Word, body: &List) -> Result<i32, String> {
+    evaluate_word(shell, init)?;
+    shell.loop_depth += 1;
+    let mut status = 0;
+    let result = loop {
+        if !test.0.trim().is_empty() {
+            match evaluate_word(shell, test) {
+                Ok(0) => break Ok(status),
+                Ok(_) => {}
+                Err(message) => break Err(message),
             }
-            
-            processes.push(process);
         }
+        status = execute_list(shell, body);
+        if end_of_pass(shell) {
+            break Ok(status);
+        }
+        if let Err(message) = evaluate_word(shell, step) {
+            break Err(message);
+        }
+    };
+    shell.loop_depth -= 1;
+    result
+}
+
+fn evaluate_word(shell: &mut Shell, expression: &Word) -> Result<i64, String> {
+    let expression = expand_arithmetic(shell, &expression.0).map_err(|error| error.into_message(shell))?;
+    evaluate(shell, &expression)
+}
+
+// Take any `break` or `continue` meant for this loop; true when the loop has to stop
+fn end_of_pass(shell: &mut Shell) -> bool {
+    match shell.unwind {
+        Some(Unwind::Break(count)) => {
+            shell.unwind = if count > 1 { Some(Unwind::Break(count - 1)) } else { None };
+            true
+        }
+        Some(Unwind::Continue(count)) if count > 1 => {
+            shell.unwind = Some(Unwind::Continue(count - 1));
+            true
+        }
+        Some(Unwind::Continue(_)) => {
+            shell.unwind = None;
+            false
+        }
+        Some(Unwind::Return) => true,
+        // Ctrl-C stops every loop, not just the command it killed
+        None => signals::interrupted(),
+    }
+}
+
+// Run a function body with its own positional parameters
+fn call_function(shell: &mut Shell, body: &ShellCommand, args: &[&str], assignments: &[(String, String)]) -> i32 {
+    let args = args.iter().map(|arg| arg.to_string()).collect();
+    let saved_positional = std::mem::replace(&mut shell.positional, args);
+
+    // Loops around the call can't be left with `break` from inside it
+    let saved_loop_depth = std::mem::replace(&mut shell.loop_depth, 0);
+    shell.function_depth += 1;
+    let status = with_assignments(shell, assignments, |shell| execute_command(shell, body));
+    shell.function_depth -= 1;
+    shell.loop_depth = saved_loop_depth;
+    if shell.unwind == Some(Unwind::Return) {
+        shell.unwind = None;
+    }
+
+    shell.positional = saved_positional;
+    status
+}
+
+// Prefix assignments that only last while `run` does, for functions and builtins like read
+fn with_assignments(shell: &mut Shell, assignments: &[(String, String)], run: impl FnOnce(&mut Shell) -> i32) -> i32 {
+    let saved_vars: Vec<(String, Option<String>)> = assignments
+        .iter()
+        .map(|(name, _)| (name.clone(), shell.vars.get(name).map(|value| value.to_string())))
+        .collect();
+    for (name, value) in assignments {
+        let _ = shell.vars.set(name, value);
     }
-    
-    // Wait for all processes to complete
-    for mut process in processes {
-        let _ = process.wait();
+    let status = run(shell);
+    for (name, value) in saved_vars.into_iter().rev() {
+        let _ = match value {
+            Some(value) => shell.vars.set(&name, &value),
+            None => shell.vars.unset(&name),
+        };
     }
+    status
 }
 
-pub fn execute_single_command(cmd_args: &[&str]) {
-    let builtins = vec!["echo", "exit", "type", "pwd", "cd"];
-    
-    if builtins.contains(&cmd_args[0]) {
-        let output = execute_builtin(cmd_args);
-        if !output.is_empty() {
-            println!("{}", output);
+pub fn execute_simple_command(shell: &mut Shell, command: &SimpleCommand) -> i32 {
+    shell.substitution_status = None;
+    let split = match expand_words(shell, &command.words) {
+        Ok(split) => split,
+        Err(error) => {
+            eprintln!("{}", error.into_message(shell));
+            return 1;
+        }
+    };
+    // Ctrl-C during a command substitution abandons the command
+    if signals::interrupted() {
+        return 130;
+    }
+    // Prefix assignments that only apply to the command being run
+    let mut assignments: Vec<(String, String)> = Vec::new();
+    for assignment in &command.assignments {
+        let value = match expand_assignment(shell, &assignment.value) {
+            Ok(value) => value,
+            Err(error) => {
+                eprintln!("{}", error.into_message(shell));
+                return 1;
+            }
+        };
+        if split.is_empty() {
+            // Plain assignments stick, each one seeing the ones before it
+            if let Err(message) = shell.vars.set(&assignment.name, &value) {
+                eprintln!("{}", message);
+                return 1;
+            }
+        } else if shell.vars.is_readonly(&assignment.name) {
+            eprintln!("{}: readonly variable", assignment.name);
+            return 1;
+        } else {
+            assignments.push((assignment.name.clone(), value));
+        }
+    }
+    // Restores the shell's fds when it goes out of scope
+    let _saved_fds = match apply_redirections(shell, &command.redirects) {
+        Ok(saved_fds) => saved_fds,
+        Err(message) => {
+            eprintln!("{}", message);
+            return 1;
+        }
+    };
+    let args: Vec<&str> = split.iter().map(|s| s.as_str()).collect();
+
+    // Functions take precedence over builtins and external commands
+    if let Some(body) = args.first().and_then(|name| shell.functions.get(*name)).cloned() {
+        return call_function(shell, &body, &args[1..], &assignments);
+    }
+
+    match args.as_slice() {
+        // Assignments and redirections only, nothing to run
+        [] => shell.substitution_status.unwrap_or(0),
+        // type
+        ["type", args @ ..] => cmd_type(shell, args),
+        // echo
+        ["echo", args @ ..] => {
+            let result = cmd_echo(args);
+            println!("{}", result);
+            0
+        }
+        // exit
+        [name @ ("exit" | "logout"), args @ ..] => cmd_exit(shell, name, args),
+        // trap
+        ["trap", args @ ..] => cmd_trap(shell, args),
+        // pwd
+        ["pwd"] => match env::current_dir() {
+            Ok(path) => {
+                println!("{}", path.display());
+                0
+            }
+            Err(error) => {
+                eprintln!("pwd: {}", io_error_message(&error));
+                1
+            }
+        },
+        // cd
+        ["cd", args @ ..] => cmd_cd(shell, args),
+        // variables
+        ["export", args @ ..] => cmd_export(shell, args),
+        ["unset", args @ ..] => cmd_unset(shell, args),
+        ["readonly", args @ ..] => cmd_readonly(shell, args),
+        ["set", args @ ..] => cmd_set(shell, args),
+        ["shift", args @ ..] => cmd_shift(shell, args),
+        ["shopt", args @ ..] => cmd_shopt(shell, args),
+        // functions
+        ["return", args @ ..] => cmd_return(shell, args),
+        // loops
+        [name @ ("break" | "continue"), args @ ..] => cmd_loop_control(shell, name, args),
+        // `:` does nothing and succeeds, as in `while :; do`
+        [":", ..] => 0,
+        ["read", args @ ..] => with_assignments(shell, &assignments, |shell| cmd_read(shell, args)),
+        // history
+        ["history", option @ ("-r" | "-w" | "-a"), file_path, ..] => {
+            cmd_history_file(shell, option, file_path)
+        }
+        ["history", args @ ..] => {
+            let history = shell.history_entries();
+            cmd_history(&history, args);
+            0
+        }
+        // Logic for spawning process
+        [cmd_name, cmd_args @ ..] => {
+            run_external_command(shell, cmd_name, cmd_args, &assignments)
         }
-    } else {
-        run_external_command(cmd_args[0], &cmd_args[1..], None, None);
     }
 }
 
+// Convert a process exit status to a shell status, 128+N for a signal
+pub fn exit_code(status: ExitStatus) -> i32 {
+    match status.code() {
+        Some(code) => code,
+        None => 128 + status.signal().unwrap_or(0),
+    }
+}
+
+// Wait for a foreground child, noting whether Ctrl-C killed it
+fn wait_pid(pid: i32) -> i32 {
+    let mut raw_status = 0;
+    loop {
+        if unsafe { libc::waitpid(pid, &mut raw_status, 0) } >= 0 {
+            break;
+        }
+        if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+            return 1;
+        }
+    }
+    let status = ExitStatus::from_raw(raw_status);
+    // Only Ctrl-C at the terminal stops the commands that follow. The newline
+    // after `^C` is printed once, however many pipeline stages it killed.
+    if status.signal() == Some(libc::SIGINT) && signals::is_interactive() {
+        if !signals::interrupted() {
+            println!();
+        }
+        signals::set_interrupted();
+    }
+    exit_code(status)
+}
+
+// Report why a command could not be started and pick the POSIX status for it
+fn spawn_error_status(cmd_name: &str, error: &io::Error) -> i32 {
+    match error.kind() {
+        io::ErrorKind::NotFound => {
+            eprintln!("{}: command not found", cmd_name);
+            127
+        }
+        io::ErrorKind::PermissionDenied => {
+            eprintln!("{}: Permission denied", cmd_name);
+            126
+        }
+        _ => {
+            eprintln!("{}: {}", cmd_name, error);
+            126
+        }
+    }
+}
+
+// Resolve a command name against PATH, names containing a slash are used as-is
+fn find_command(cmd_name: &str, path_var: &str) -> Option<PathBuf> {
+    if cmd_name.contains('/') {
+        return Some(PathBuf::from(cmd_name));
+    }
+    let mut candidates = path_var
+        .split(':')
+        .map(|dir| Path::new(dir).join(cmd_name))
+        .filter(|full_path| full_path.is_file());
+    let first = candidates.next()?;
+    // Prefer an executable match, but remember a non-executable one for the 126 error
+    if is_executable(&first) {
+        return Some(first);
+    }
+    candidates.find(|full_path| is_executable(full_path)).or(Some(first))
+}
+
 pub fn run_external_command(
+    shell: &Shell,
     cmd_name: &str,
     args: &[&str],
-    stdout_file: Option<&(String, bool)>,
-    stderr_file: Option<&(String, bool)>
-) {
-    
-    if let Ok(path_var) = env::var("PATH") {
-        for dir in path_var.split(':') {
-            let full_path = Path::new(dir).join(cmd_name);
-            if let Ok(metadata) = std::fs::metadata(&full_path) {
-                if metadata.is_file() {
-                    let mut command = Command::new(cmd_name);
-                    command.args(args);
-
-                    // Redirect stdout to file (append or overwrite)
-                    if let Some((file_path, append)) = stdout_file {
-                        if let Some(parent) = Path::new(file_path).parent() {
-                            let _ = std::fs::create_dir_all(parent);
-                        }
-                        let file = if *append {
-                            OpenOptions::new().create(true).append(true).open(file_path)
-                        } else {
-                            File::create(file_path)
-                        };
-                        if let Ok(f) = file {
-                            command.stdout(Stdio::from(f));
-                        } else {
-                            println!("Error opening stdout file: {}", file_path);
-                            return;
-                        }
-                    }
+    prefix_assignments: &[(String, String)],
+) -> i32 {
+    let full_path = match find_command(cmd_name, shell.vars.get("PATH").unwrap_or_default()) {
+        Some(full_path) => full_path,
+        None => {
+            eprintln!("{}: command not found", cmd_name);
+            return 127;
+        }
+    };
 
-                    // Redirect stderr to file (append or overwrite)
-                    if let Some((file_path, append)) = stderr_file {
-                        if let Some(parent) = Path::new(file_path).parent() {
-                            let _ = std::fs::create_dir_all(parent);
-                        }
-                        let file = if *append {
-                            OpenOptions::new().create(true).append(true).open(file_path)
-                        } else {
-                            File::create(file_path)
-                        };
-                        match file {
-                            Ok(file) => {
-                                command.stderr(Stdio::from(file));
-                            }
-                            Err(e) => {
-                                println!("Error opening stderr file {}: {}", file_path, e);
-                                return;
-                            }
-                        }
-                    }
+    let mut command = Command::new(&full_path);
+    command.arg0(cmd_name);
+    command.args(args);
+    // The child sees exported variables plus this command's own assignments
+    command.env_clear();
+    command.envs(shell.vars.exported());
+    command.envs(prefix_assignments.iter().map(|(name, value)| (name, value)));
 
-                    let mut process = command.spawn().unwrap();
-                    let _status = process.wait().unwrap();
-                    return;
-                }
-            }
+    signals::prepare_foreground(&mut command, 0);
+    match command.spawn() {
+        Ok(process) => {
+            signals::foreground_job(process.id(), 0);
+            let status = wait_pid(process.id() as i32);
+            signals::reclaim_terminal();
+            status
         }
+        Err(e) => spawn_error_status(cmd_name, &e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Run a script in a fresh shell and return it for inspection
+    fn run(script: &str) -> Shell {
+        let mut shell = Shell::new();
+        let list = parse(script).unwrap();
+        execute_list(&mut shell, &list);
+        shell
+    }
+
+    fn var(shell: &Shell, name: &str) -> String {
+        shell.vars.get(name).unwrap_or_default().to_string()
+    }
+
+    #[test]
+    fn while_and_until() {
+        let shell = run("i=0; out=; while ((i < 3)); do out=$out$i; i=$((i + 1)); done");
+        assert_eq!(var(&shell, "out"), "012");
+        let shell = run("i=0; until ((i == 3)); do i=$((i + 1)); done; status=$?");
+        assert_eq!(var(&shell, "i"), "3");
+        assert_eq!(var(&shell, "status"), "0");
+        // The loop's status is the last body's, or 0 if the body never ran
+        let shell = run("while false; do :; done");
+        assert_eq!(shell.last_status, 0);
+    }
+
+    #[test]
+    fn break_and_continue() {
+        let shell = run("i=0; out=; while :; do i=$((i + 1)); ((i == 2)) && continue; ((i > 4)) && break; out=$out$i; done");
+        assert_eq!(var(&shell, "out"), "134");
+        let script = "out=; i=0; while ((i < 3)); do i=$((i + 1)); j=0
+            while :; do j=$((j + 1)); ((j == 2)) && continue 2; ((i == 3)) && break 2; out=$out$i$j; done
+        done";
+        let shell = run(script);
+        assert_eq!(var(&shell, "out"), "1121");
+        assert_eq!(var(&shell, "i"), "3");
+        assert_eq!(shell.loop_depth, 0);
+    }
+
+    #[test]
+    fn break_outside_a_loop_is_harmless() {
+        let shell = run("break; after=yes");
+        assert_eq!(var(&shell, "after"), "yes");
+        assert!(shell.unwind.is_none());
+    }
+
+    #[test]
+    fn for_over_words() {
+        let shell = run("out=; for x in a{1,2} 'b c' $empty; do out=\"$out[$x]\"; done");
+        assert_eq!(var(&shell, "out"), "[a1][a2][b c]");
+        let shell = run("v='1 2'; n=0; for x in $v \"$v\"; do n=$((n + 1)); done");
+        assert_eq!(var(&shell, "n"), "3");
+        // The variable keeps the last value after the loop
+        assert_eq!(var(&shell, "x"), "1 2");
+    }
+
+    #[test]
+    fn for_over_positional_parameters() {
+        let mut shell = Shell::new();
+        shell.positional = vec!["a".to_string(), "b c".to_string()];
+        let list = parse("out=; for x; do out=\"$out[$x]\"; done").unwrap();
+        execute_list(&mut shell, &list);
+        assert_eq!(var(&shell, "out"), "[a][b c]");
+    }
+
+    #[test]
+    fn arithmetic_for() {
+        let shell = run("out=; for ((i = 0; i < 5; i += 2)); do out=$out$i; done");
+        assert_eq!(var(&shell, "out"), "024");
+        // An empty test runs until break
+        let shell = run("n=0; for ((;;)); do n=$((n + 1)); ((n == 4)) && break; done");
+        assert_eq!(var(&shell, "n"), "4");
+    }
+
+    #[test]
+    fn case_patterns() {
+        let classify = |word: &str| {
+            let script = format!(
+                "case {} in [0-9]*) kind=number;; *.rs|*.toml) kind=rust;; '*') kind=star;; \"\") kind=empty;; *) kind=other;; esac",
+                word
+            );
+            var(&run(&script), "kind")
+        };
+        assert_eq!(classify("42"), "number");
+        assert_eq!(classify("main.rs"), "rust");
+        assert_eq!(classify("Cargo.toml"), "rust");
+        assert_eq!(classify("'*'"), "star");
+        assert_eq!(classify("\"\""), "empty");
+        assert_eq!(classify("x"), "other");
+    }
+
+    #[test]
+    fn case_pattern_from_variable() {
+        let shell = run("p='a*'; case abc in $p) m=glob;; esac; case abc in \"$p\") q=literal;; esac");
+        assert_eq!(var(&shell, "m"), "glob");
+        assert_eq!(var(&shell, "q"), "");
+    }
+
+    #[test]
+    fn case_terminators() {
+        let shell = run("out=; case a in a) out=${out}1;& b) out=${out}2;; c) out=${out}3;; esac");
+        assert_eq!(var(&shell, "out"), "12");
+        let shell = run("out=; case ab in a*) out=${out}1;;& b*) out=${out}2;;& *b) out=${out}3;; *) out=${out}4;; esac");
+        assert_eq!(var(&shell, "out"), "13");
+        // No match leaves status 0
+        let shell = run("false; case z in a) :;; esac");
+        assert_eq!(shell.last_status, 0);
     }
-    println!("{}: command not found", cmd_name);
 }
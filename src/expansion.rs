@@ -0,0 +1,1201 @@
+use std::ffi::{CStr, CString};
+use thiserror::Error;
+use crate::arith::evaluate;
+use crate::ast::Word;
+use crate::braces::expand_braces;
+use crate::execution::capture_output;
+use crate::glob;
+use crate::pattern::{self, Pattern};
+use crate::shell::Shell;
+use crate::variables::is_valid_name;
+
+#[derive(Debug, Error)]
+pub enum ExpansionError {
+    #[error("{0}")]
+    Failed(String),
+    // `${var:?message}` on an unset or empty variable
+    #[error("{0}")]
+    ParameterUnset(String),
+}
+
+impl From<String> for ExpansionError {
+    fn from(message: String) -> Self {
+        ExpansionError::Failed(message)
+    }
+}
+
+impl ExpansionError {
+    // The message for the caller to print, except that a failed `${var:?}`
+    // prints it and ends a non-interactive shell straight away, as POSIX says
+    pub fn into_message(self, shell: &mut Shell) -> String {
+        match self {
+            ExpansionError::ParameterUnset(message) if !shell.interactive => {
+                eprintln!("{}", message);
+                shell.shutdown(1)
+            }
+            ExpansionError::Failed(message) | ExpansionError::ParameterUnset(message) => message,
+        }
+    }
+}
+
+// Turn a raw word into a single string, as for redirection targets
+pub fn expand_word(shell: &mut Shell, word: &Word) -> Result<String, ExpansionError> {
+    Ok(join_fields(Expander::new(shell, &word.0, Mode::Word).run()?).text())
+}
+
+// A `case` pattern, where quoted characters only match themselves
+pub fn expand_pattern(shell: &mut Shell, word: &Word) -> Result<String, ExpansionError> {
+    Ok(join_fields(Expander::new(shell, &word.0, Mode::Word).run()?).pattern())
+}
+
+// The value of an assignment, where a tilde can also follow each `:` as in PATH
+pub fn expand_assignment(shell: &mut Shell, word: &Word) -> Result<String, ExpansionError> {
+    Ok(join_fields(Expander::new(shell, &word.0, Mode::Assignment).run()?).text())
+}
+
+// Expand command words into arguments: braces first, then the `$` expansions,
+// splitting unquoted results on IFS, dropping unquoted words that expand to
+// nothing and replacing patterns with the paths they match
+pub fn expand_words(shell: &mut Shell, words: &[Word]) -> Result<Vec<String>, ExpansionError> {
+    let mut fields = Vec::new();
+    let raw_words = words.iter().flat_map(|word| expand_braces(&word.0));
+    for raw in raw_words {
+        let ifs = shell.vars.get("IFS").unwrap_or(" \t\n").to_string();
+        let expanded_fields = Expander::new(shell, &raw, Mode::Word).run()?;
+        for expanded in expanded_fields.into_iter().flat_map(|expanded| expanded.split(&ifs)) {
+            if !expanded.has_wildcards() {
+                fields.push(expanded.text());
+                continue;
+            }
+            let options = &shell.glob_options;
+            let matches = glob::expand(&expanded.pattern(), options);
+            if !matches.is_empty() {
+                fields.extend(matches);
+            } else if options.failglob {
+                return Err(format!("no match: {}", expanded.text()).into());
+            } else if !options.nullglob {
+                fields.push(expanded.text());
+            }
+        }
+    }
+    Ok(fields)
+}
+
+// An arithmetic expression before evaluation, where `~` is an operator rather than a directory
+pub fn expand_arithmetic(shell: &mut Shell, raw: &str) -> Result<String, ExpansionError> {
+    Ok(join_fields(Expander::new(shell, raw, Mode::Arithmetic).run()?).text())
+}
+
+// Here-document bodies only treat `\` before `$`, `` ` ``, `\` or newline specially
+pub fn expand_heredoc(shell: &mut Shell, body: &str) -> Result<String, ExpansionError> {
+    Ok(join_fields(Expander::new(shell, body, Mode::HereDoc).run()?).text())
+}
+
+// Home directory of a user from the passwd database, or of the current user
+fn home_directory(user: Option<&str>) -> Option<String> {
+    let entry = match user {
+        Some(user) => {
+            let name = CString::new(user).ok()?;
+            unsafe { libc::getpwnam(name.as_ptr()) }
+        }
+        None => unsafe { libc::getpwuid(libc::getuid()) },
+    };
+    if entry.is_null() {
+        return None;
+    }
+    let directory = unsafe { CStr::from_ptr((*entry).pw_dir) };
+    directory.to_str().ok().map(String::from)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Word,
+    Assignment,
+    Arithmetic,
+    HereDoc,
+}
+
+// One character of expanded text, remembering whether quoting protected it
+#[derive(Debug, Clone, Copy)]
+struct Piece {
+    c: char,
+    quoted: bool,
+    // Produced by an unquoted expansion, so subject to field splitting
+    split: bool,
+}
+
+struct Expanded {
+    pieces: Vec<Piece>,
+    // The word contained quotes, so an empty result still counts as an argument
+    quoted: bool,
+    // Positions of quotes that produced no text, like `""`, which still start a field
+    empty_quotes: Vec<usize>,
+}
+
+impl Expanded {
+    // Field splitting, keeping only the fields that become arguments. Runs of
+    // IFS whitespace separate fields and are dropped at either end, while any
+    // other IFS character ends a field even when it is empty.
+    fn split(self, ifs: &str) -> Vec<Expanded> {
+        let is_separator = |piece: &Piece| piece.split && ifs.contains(piece.c);
+        if !self.pieces.iter().any(is_separator) {
+            if self.pieces.is_empty() && !self.quoted {
+                return Vec::new();
+            }
+            return vec![self];
+        }
+
+        let mut fields = Vec::new();
+        let mut current = Vec::new();
+        // Text or quotes since the last separator
+        let mut started = false;
+        // Whitespace just ended a field, which a following `,` in IFS=" ," joins
+        let mut after_blank = false;
+        for (i, piece) in self.pieces.iter().enumerate() {
+            started |= self.empty_quotes.contains(&i);
+            if !is_separator(piece) {
+                current.push(*piece);
+                started = true;
+                after_blank = false;
+            } else if matches!(piece.c, ' ' | '\t' | '\n') {
+                if started {
+                    fields.push(Expanded::field(std::mem::take(&mut current)));
+                    started = false;
+                    after_blank = true;
+                }
+            } else {
+                if started || !after_blank {
+                    fields.push(Expanded::field(std::mem::take(&mut current)));
+                }
+                started = false;
+                after_blank = false;
+            }
+        }
+        if started || self.empty_quotes.contains(&self.pieces.len()) {
+            fields.push(Expanded::field(current));
+        }
+        fields
+    }
+
+    // A field cut out by splitting, which is an argument even when empty
+    fn field(pieces: Vec<Piece>) -> Expanded {
+        Expanded {
+            pieces,
+            quoted: true,
+            empty_quotes: Vec::new(),
+        }
+    }
+
+    fn text(&self) -> String {
+        self.pieces.iter().map(|piece| piece.c).collect()
+    }
+
+    // Unquoted `*`, `?` or `[...]` make the field a pathname pattern
+    fn has_wildcards(&self) -> bool {
+        let unquoted = |c: char| self.pieces.iter().any(|piece| !piece.quoted && piece.c == c);
+        if unquoted('*') || unquoted('?') {
+            return true;
+        }
+        unquoted('[') && pattern::has_wildcards(&self.pattern())
+    }
+
+    // Pattern source where quoted characters only match themselves
+    fn pattern(&self) -> String {
+        let mut pattern = String::new();
+        for piece in &self.pieces {
+            if piece.quoted && matches!(piece.c, '*' | '?' | '[' | ']' | '\\') {
+                pattern.push('\\');
+            }
+            pattern.push(piece.c);
+        }
+        pattern
+    }
+}
+
+// What `${name...}` does with the parameter's value
+enum Operation<'a> {
+    Value,
+    Length,
+    // `-`, `=`, `?` or `+`, with or without a leading `:`
+    Test { op: char, colon: bool, word: &'a str },
+    RemovePrefix { longest: bool, pattern: &'a str },
+    RemoveSuffix { longest: bool, pattern: &'a str },
+    Replace { mode: ReplaceMode, pattern: &'a str, replacement: &'a str },
+    Substring { offset: &'a str, length: Option<&'a str> },
+    Case { upper: bool, all: bool, pattern: &'a str },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReplaceMode {
+    First,
+    All,
+    Prefix,
+    Suffix,
+}
+
+struct Expander<'a> {
+    shell: &'a mut Shell,
+    chars: Vec<char>,
+    pos: usize,
+    mode: Mode,
+    // Finished fields, "$@" can turn one word into several
+    fields: Vec<Expanded>,
+    // The field being built
+    pieces: Vec<Piece>,
+    quoted: bool,
+    empty_quotes: Vec<usize>,
+    // Expansion results are protected from globbing inside double quotes
+    in_double_quotes: bool,
+    // "$@" with no positional parameters, which makes no field at all
+    empty_at: bool,
+}
+
+impl<'a> Expander<'a> {
+    fn new(shell: &'a mut Shell, raw: &str, mode: Mode) -> Self {
+        Expander {
+            shell,
+            chars: raw.chars().collect(),
+            pos: 0,
+            mode,
+            fields: Vec::new(),
+            pieces: Vec::new(),
+            quoted: false,
+            empty_quotes: Vec::new(),
+            in_double_quotes: false,
+            empty_at: false,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek();
+        if c.is_some() {
+            self.pos += 1;
+        }
+        c
+    }
+
+    fn push(&mut self, c: char, quoted: bool) {
+        self.pieces.push(Piece { c, quoted, split: false });
+    }
+
+    // Text produced by an expansion rather than written literally
+    fn push_value(&mut self, value: &str) {
+        for c in value.chars() {
+            self.pieces.push(Piece {
+                c,
+                quoted: self.in_double_quotes,
+                split: !self.in_double_quotes,
+            });
+        }
+    }
+
+    // Remember quotes that added nothing, since they still make a field
+    fn note_empty_quotes(&mut self, pieces_before: usize) {
+        if self.pieces.len() == pieces_before {
+            self.empty_quotes.push(pieces_before);
+        }
+    }
+
+    // Start a new field, as between the words of "$@"
+    fn break_field(&mut self) {
+        let pieces = std::mem::take(&mut self.pieces);
+        self.fields.push(Expanded {
+            pieces,
+            quoted: self.quoted,
+            empty_quotes: std::mem::take(&mut self.empty_quotes),
+        });
+    }
+
+    fn run(mut self) -> Result<Vec<Expanded>, ExpansionError> {
+        if matches!(self.mode, Mode::Word | Mode::Assignment) {
+            self.tilde();
+        }
+        while let Some(c) = self.bump() {
+            match c {
+                '\\' if self.mode == Mode::HereDoc => match self.peek() {
+                    Some(next @ ('$' | '`' | '\\')) => {
+                        self.push(next, true);
+                        self.pos += 1;
+                    }
+                    Some('\n') => self.pos += 1,
+                    _ => self.push('\\', true),
+                },
+                '\\' => {
+                    self.quoted = true;
+                    if let Some(next) = self.bump() {
+                        self.push(next, true);
+                    }
+                }
+                ':' if self.mode == Mode::Assignment => {
+                    self.push(':', false);
+                    self.tilde();
+                }
+                '\'' if self.mode != Mode::HereDoc => {
+                    let pieces_before = self.pieces.len();
+                    self.quoted = true;
+                    while let Some(inner) = self.bump() {
+                        if inner == '\'' {
+                            break;
+                        }
+                        self.push(inner, true);
+                    }
+                    self.note_empty_quotes(pieces_before);
+                }
+                '$' if self.mode != Mode::HereDoc && self.peek() == Some('\'') => {
+                    let pieces_before = self.pieces.len();
+                    self.pos += 1;
+                    self.quoted = true;
+                    self.ansi_c_quoted();
+                    self.note_empty_quotes(pieces_before);
+                }
+                '"' if self.mode != Mode::HereDoc => {
+                    let (quoted_before, pieces_before) = (self.quoted, self.pieces.len());
+                    self.quoted = true;
+                    self.in_double_quotes = true;
+                    self.empty_at = false;
+                    let result = self.double_quoted();
+                    self.in_double_quotes = false;
+                    result?;
+                    if self.empty_at && self.pieces.len() == pieces_before {
+                        self.quoted = quoted_before;
+                    } else {
+                        self.note_empty_quotes(pieces_before);
+                    }
+                }
+                '$' => self.dollar()?,
+                '`' => self.backquoted()?,
+                _ => self.push(c, self.mode == Mode::HereDoc),
+            }
+        }
+        self.break_field();
+        Ok(self.fields)
+    }
+
+    // `~`, `~user`, `~+` or `~-` up to the next `/`, replaced by a directory.
+    // Any quoting in the prefix, or an unknown user, leaves it as it is.
+    fn tilde(&mut self) {
+        if self.peek() != Some('~') {
+            return;
+        }
+        let start = self.pos + 1;
+        let end = (start..self.chars.len())
+            .find(|&i| self.chars[i] == '/' || (self.mode == Mode::Assignment && self.chars[i] == ':'))
+            .unwrap_or(self.chars.len());
+        let prefix: String = self.chars[start..end].iter().collect();
+        if prefix.contains(['\\', '\'', '"', '$', '`']) {
+            return;
+        }
+        let directory = match prefix.as_str() {
+            "" => self.shell.vars.get("HOME").map(String::from).or_else(|| home_directory(None)),
+            "+" => self.shell.vars.get("PWD").map(String::from),
+            "-" => self.shell.vars.get("OLDPWD").map(String::from),
+            user => home_directory(Some(user)),
+        };
+        if let Some(directory) = directory {
+            // The directory is used as it is, without splitting or globbing
+            for c in directory.chars() {
+                self.push(c, true);
+            }
+            self.pos = end;
+        }
+    }
+
+    // `$'...'`, where backslash escapes are decoded as in C strings
+    fn ansi_c_quoted(&mut self) {
+        while let Some(c) = self.bump() {
+            match c {
+                '\'' => return,
+                '\\' => self.ansi_c_escape(),
+                _ => self.push(c, true),
+            }
+        }
+    }
+
+    // Called just past the backslash; unknown escapes stay as they are
+    fn ansi_c_escape(&mut self) {
+        let Some(c) = self.bump() else {
+            self.push('\\', true);
+            return;
+        };
+        let decoded = match c {
+            'a' => Some('\x07'),
+            'b' => Some('\x08'),
+            'e' | 'E' => Some('\x1b'),
+            'f' => Some('\x0c'),
+            'n' => Some('\n'),
+            'r' => Some('\r'),
+            't' => Some('\t'),
+            'v' => Some('\x0b'),
+            '\\' | '\'' | '"' | '?' => Some(c),
+            '0'..='7' => {
+                self.pos -= 1;
+                self.escape_number(8, 3)
+            }
+            'x' => self.escape_number(16, 2),
+            'u' => self.escape_number(16, 4),
+            'U' => self.escape_number(16, 8),
+            // `\cX` is the control character for X
+            'c' => match self.bump() {
+                Some(control) if control.is_ascii() => char::from_u32(control.to_ascii_uppercase() as u32 ^ 0x40),
+                _ => None,
+            },
+            _ => None,
+        };
+        match decoded {
+            // NUL can't be part of an argument
+            Some('\0') => {}
+            Some(decoded) => self.push(decoded, true),
+            None if c != 'c' => {
+                self.push('\\', true);
+                self.push(c, true);
+            }
+            None => {}
+        }
+    }
+
+    // Up to `max_digits` digits in `radix` as a character; None without any digits
+    fn escape_number(&mut self, radix: u32, max_digits: usize) -> Option<char> {
+        let mut value: Option<u32> = None;
+        for _ in 0..max_digits {
+            let Some(digit) = self.peek().and_then(|c| c.to_digit(radix)) else {
+                break;
+            };
+            value = Some(value.unwrap_or(0).wrapping_mul(radix).wrapping_add(digit));
+            self.pos += 1;
+        }
+        // Invalid code points are dropped
+        Some(char::from_u32(value?).unwrap_or('\0'))
+    }
+
+    fn double_quoted(&mut self) -> Result<(), ExpansionError> {
+        while let Some(c) = self.bump() {
+            match c {
+                '"' => return Ok(()),
+                // Inside double quotes a backslash only escapes a few characters
+                '\\' => match self.peek() {
+                    Some(next @ ('$' | '`' | '"' | '\\')) => {
+                        self.push(next, true);
+                        self.pos += 1;
+                    }
+                    Some('\n') => self.pos += 1,
+                    _ => self.push('\\', true),
+                },
+                '$' => self.dollar()?,
+                '`' => self.backquoted()?,
+                _ => self.push(c, true),
+            }
+        }
+        Ok(())
+    }
+
+    // Called just past a `$`
+    fn dollar(&mut self) -> Result<(), ExpansionError> {
+        match self.peek() {
+            Some('{') => {
+                self.pos += 1;
+                let inner = self.braced_text();
+                self.parameter_expansion(&inner)?;
+            }
+            Some('(') => {
+                self.pos += 1;
+                let source = self.parenthesized_text();
+                // $((expr)) is arithmetic, anything else in $(...) is a command
+                let value = match source.strip_prefix('(').and_then(|inner| inner.strip_suffix(')')) {
+                    Some(expression) => {
+                        let expression = expand_arithmetic(self.shell, expression)?;
+                        evaluate(self.shell, &expression)?.to_string()
+                    }
+                    None => capture_output(self.shell, &source)?,
+                };
+                self.push_value(&value);
+            }
+            Some(c @ ('?' | '$' | '!' | '#' | '-' | '0'..='9')) => {
+                self.pos += 1;
+                let value = self.lookup(&c.to_string()).unwrap_or_default();
+                self.push_value(&value);
+            }
+            Some(c @ ('@' | '*')) => {
+                self.pos += 1;
+                self.push_positional(c);
+            }
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+                let start = self.pos;
+                while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == '_') {
+                    self.pos += 1;
+                }
+                let name: String = self.chars[start..self.pos].iter().collect();
+                let value = self.lookup(&name).unwrap_or_default();
+                self.push_value(&value);
+            }
+            // A lone `$` is literal
+            _ => self.push('$', self.in_double_quotes),
+        }
+        Ok(())
+    }
+
+    // Everything up to the `}` matching an already consumed `${`, which is skipped too
+    fn braced_text(&mut self) -> String {
+        let start = self.pos;
+        let mut depth = 0;
+        while let Some(c) = self.bump() {
+            match c {
+                '\\' => {
+                    self.pos += 1;
+                }
+                '\'' if !self.in_double_quotes => {
+                    while !matches!(self.bump(), None | Some('\'')) {}
+                }
+                '"' => {
+                    while let Some(inner) = self.bump() {
+                        match inner {
+                            '\\' => self.pos += 1,
+                            '"' => break,
+                            _ => {}
+                        }
+                    }
+                }
+                '$' if self.peek() == Some('{') => {
+                    self.pos += 1;
+                    depth += 1;
+                }
+                '}' if depth == 0 => {
+                    return self.chars[start..self.pos - 1].iter().collect();
+                }
+                '}' => depth -= 1,
+                _ => {}
+            }
+        }
+        self.pos = self.pos.min(self.chars.len());
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    // The command inside `$(...)`, leaving the position past the closing `)`
+    fn parenthesized_text(&mut self) -> String {
+        let start = self.pos;
+        let mut depth = 1;
+        while let Some(c) = self.bump() {
+            match c {
+                '\\' => self.pos += 1,
+                '\'' => while !matches!(self.bump(), None | Some('\'')) {},
+                '"' | '`' => {
+                    while let Some(inner) = self.bump() {
+                        match inner {
+                            '\\' => self.pos += 1,
+                            _ if inner == c => break,
+                            _ => {}
+                        }
+                    }
+                }
+                '(' => depth += 1,
+                ')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return self.chars[start..self.pos - 1].iter().collect();
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.pos = self.pos.min(self.chars.len());
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    // `...`: a backslash only escapes `$`, `` ` `` and `\` (and `"` inside double quotes)
+    fn backquoted(&mut self) -> Result<(), ExpansionError> {
+        let mut source = String::new();
+        while let Some(c) = self.bump() {
+            match c {
+                '`' => break,
+                '\\' => match self.peek() {
+                    Some(next @ ('$' | '`' | '\\')) => {
+                        source.push(next);
+                        self.pos += 1;
+                    }
+                    Some('"') if self.in_double_quotes => {
+                        source.push('"');
+                        self.pos += 1;
+                    }
+                    _ => source.push('\\'),
+                },
+                _ => source.push(c),
+            }
+        }
+        let output = capture_output(self.shell, &source)?;
+        self.push_value(&output);
+        Ok(())
+    }
+
+    fn parameter_expansion(&mut self, inner: &str) -> Result<(), ExpansionError> {
+        let bad_substitution = || format!("${{{}}}: bad substitution", inner);
+        let (name, operation) = parse_parameter(inner).ok_or_else(bad_substitution)?;
+        if name.is_empty() {
+            return Err(bad_substitution().into());
+        }
+        let value = self.lookup(name);
+
+        match operation {
+            Operation::Value if matches!(name, "@" | "*") => self.push_positional(name.chars().next().unwrap_or('@')),
+            Operation::Value => self.push_value(&value.unwrap_or_default()),
+            // ${#@} and ${#*} count the positional parameters
+            Operation::Length if matches!(name, "@" | "*") => {
+                let count = self.shell.positional.len();
+                self.push_value(&count.to_string());
+            }
+            Operation::Length => {
+                let length = value.unwrap_or_default().chars().count();
+                self.push_value(&length.to_string());
+            }
+            Operation::Test { op, colon, word } => {
+                // With `:` an empty value counts as missing too
+                let missing = match &value {
+                    None => true,
+                    Some(value) => colon && value.is_empty(),
+                };
+                match op {
+                    '-' if missing => self.push_operand(word)?,
+                    '=' if missing => {
+                        if !is_valid_name(name) {
+                            return Err(format!("${}: cannot assign in this way", name).into());
+                        }
+                        let assigned = self.expand_operand(word)?.text();
+                        self.shell.vars.set(name, &assigned)?;
+                        self.push_value(&assigned);
+                    }
+                    '?' if missing => {
+                        let message = self.expand_operand(word)?.text();
+                        return Err(ExpansionError::ParameterUnset(if message.is_empty() {
+                            format!("{}: parameter null or not set", name)
+                        } else {
+                            format!("{}: {}", name, message)
+                        }));
+                    }
+                    '+' if !missing => self.push_operand(word)?,
+                    '+' => {}
+                    _ => self.push_value(&value.unwrap_or_default()),
+                }
+            }
+            Operation::RemovePrefix { longest, pattern } => {
+                let value = value.unwrap_or_default();
+                let pattern = Pattern::new(&self.expand_pattern_operand(pattern)?);
+                let rest = match pattern.match_prefix(&value, longest) {
+                    Some(end) => &value[end..],
+                    None => &value,
+                };
+                self.push_value(rest);
+            }
+            Operation::RemoveSuffix { longest, pattern } => {
+                let value = value.unwrap_or_default();
+                let pattern = Pattern::new(&self.expand_pattern_operand(pattern)?);
+                let rest = match pattern.match_suffix(&value, longest) {
+                    Some(start) => &value[..start],
+                    None => &value,
+                };
+                self.push_value(rest);
+            }
+            Operation::Replace { mode, pattern, replacement } => {
+                let value = value.unwrap_or_default();
+                let pattern = self.expand_pattern_operand(pattern)?;
+                let replacement = self.expand_operand(replacement)?.text();
+                let replaced = replace(&value, &pattern, &replacement, mode);
+                self.push_value(&replaced);
+            }
+            Operation::Substring { offset, length } => {
+                let value = value.unwrap_or_default();
+                let offset = self.substring_index(offset)?;
+                let length = match length {
+                    Some(length) => Some(self.substring_index(length)?),
+                    None => None,
+                };
+                let substring = substring(&value, offset, length)?;
+                self.push_value(&substring);
+            }
+            Operation::Case { upper, all, pattern } => {
+                let value = value.unwrap_or_default();
+                let pattern = if pattern.is_empty() {
+                    None
+                } else {
+                    Some(Pattern::new(&self.expand_pattern_operand(pattern)?))
+                };
+                let mut converted = String::new();
+                for (i, c) in value.chars().enumerate() {
+                    let selected = (all || i == 0)
+                        && pattern.as_ref().map_or(true, |pattern| pattern.matches(&c.to_string()));
+                    match (selected, upper) {
+                        (true, true) => converted.extend(c.to_uppercase()),
+                        (true, false) => converted.extend(c.to_lowercase()),
+                        (false, _) => converted.push(c),
+                    }
+                }
+                self.push_value(&converted);
+            }
+        }
+        Ok(())
+    }
+
+    // $@ and $*: one field per parameter, except "$*" which joins them with the first IFS character
+    fn push_positional(&mut self, c: char) {
+        let params = self.shell.positional.clone();
+        if c == '*' && self.in_double_quotes {
+            let joined = params.join(&self.ifs_separator());
+            self.push_value(&joined);
+            return;
+        }
+        if params.is_empty() && self.in_double_quotes {
+            self.empty_at = true;
+        }
+        for (i, param) in params.iter().enumerate() {
+            if i > 0 {
+                self.break_field();
+            }
+            self.push_value(param);
+        }
+    }
+
+    // IFS unset means a space, an empty IFS means no separator
+    fn ifs_separator(&self) -> String {
+        match self.shell.vars.get("IFS") {
+            Some(ifs) => ifs.chars().next().map(String::from).unwrap_or_default(),
+            None => " ".to_string(),
+        }
+    }
+
+    // Expand the word inside `${...}` with the same shell, quotes and all
+    fn expand_operand(&mut self, raw: &str) -> Result<Expanded, ExpansionError> {
+        let mut expanded = join_fields(Expander::new(self.shell, raw, Mode::Word).run()?);
+        if self.in_double_quotes {
+            for piece in &mut expanded.pieces {
+                piece.quoted = true;
+                piece.split = false;
+            }
+        }
+        Ok(expanded)
+    }
+
+    // Patterns keep their own quoting: double quotes around the whole
+    // expansion apply to its result, so `"${path##*/}"` still matches
+    fn expand_pattern_operand(&mut self, raw: &str) -> Result<String, ExpansionError> {
+        Ok(join_fields(Expander::new(self.shell, raw, Mode::Word).run()?).pattern())
+    }
+
+    fn push_operand(&mut self, raw: &str) -> Result<(), ExpansionError> {
+        let mut expanded = self.expand_operand(raw)?;
+        // Unquoted text in the operand is split like the expansion it replaces
+        for piece in &mut expanded.pieces {
+            piece.split = !piece.quoted;
+        }
+        self.quoted |= expanded.quoted;
+        self.pieces.extend(expanded.pieces);
+        Ok(())
+    }
+
+    // Substring offsets and lengths are arithmetic expressions
+    fn substring_index(&mut self, raw: &str) -> Result<i64, ExpansionError> {
+        let expression = expand_arithmetic(self.shell, raw)?;
+        Ok(evaluate(self.shell, &expression)?)
+    }
+
+    // A parameter's value, or None when it is unset
+    fn lookup(&self, name: &str) -> Option<String> {
+        let shell = &self.shell;
+        match name {
+            "?" => Some(shell.last_status.to_string()),
+            "$" => Some(shell.pid.to_string()),
+            "!" => shell.last_background_pid.map(|pid| pid.to_string()),
+            "#" => Some(shell.positional.len().to_string()),
+            "-" => Some(if shell.interactive { "i" } else { "" }.to_string()),
+            "0" => Some(shell.script_name.clone()),
+            "@" | "*" if shell.positional.is_empty() => None,
+            "@" => Some(shell.positional.join(" ")),
+            "*" => Some(shell.positional.join(&self.ifs_separator())),
+            _ if name.chars().all(|c| c.is_ascii_digit()) => {
+                let index: usize = name.parse().ok()?;
+                shell.positional.get(index.checked_sub(1)?).cloned()
+            }
+            _ => shell.vars.get(name).map(|value| value.to_string()),
+        }
+    }
+}
+
+// Several fields run together with spaces, where a single string is wanted
+fn join_fields(fields: Vec<Expanded>) -> Expanded {
+    let mut joined = Expanded {
+        pieces: Vec::new(),
+        quoted: false,
+        empty_quotes: Vec::new(),
+    };
+    for (i, field) in fields.into_iter().enumerate() {
+        if i > 0 {
+            joined.pieces.push(Piece {
+                c: ' ',
+                quoted: true,
+                split: false,
+            });
+        }
+        joined.pieces.extend(field.pieces);
+        joined.quoted |= field.quoted;
+    }
+    joined
+}
+
+// Split the inside of `${...}` into the parameter name and what to do with it
+fn parse_parameter(inner: &str) -> Option<(&str, Operation<'_>)> {
+    if let Some(name) = inner.strip_prefix('#') {
+        if !name.is_empty() {
+            return Some((parameter_name(name).filter(|n| n.len() == name.len())?, Operation::Length));
+        }
+    }
+    let name = parameter_name(inner)?;
+    let rest = &inner[name.len()..];
+    let mut chars = rest.chars();
+    let operation = match chars.next() {
+        None => Operation::Value,
+        Some(':') => match chars.next() {
+            Some(op @ ('-' | '=' | '?' | '+')) => Operation::Test {
+                op,
+                colon: true,
+                word: &rest[2..],
+            },
+            _ => {
+                let (offset, length) = match rest[1..].split_once(':') {
+                    Some((offset, length)) => (offset, Some(length)),
+                    None => (&rest[1..], None),
+                };
+                Operation::Substring { offset, length }
+            }
+        },
+        Some(op @ ('-' | '=' | '?' | '+')) => Operation::Test {
+            op,
+            colon: false,
+            word: &rest[1..],
+        },
+        Some('#') => match rest.strip_prefix("##") {
+            Some(pattern) => Operation::RemovePrefix { longest: true, pattern },
+            None => Operation::RemovePrefix { longest: false, pattern: &rest[1..] },
+        },
+        Some('%') => match rest.strip_prefix("%%") {
+            Some(pattern) => Operation::RemoveSuffix { longest: true, pattern },
+            None => Operation::RemoveSuffix { longest: false, pattern: &rest[1..] },
+        },
+        Some('/') => {
+            let body = &rest[1..];
+            let (mode, body) = match body.chars().next() {
+                Some('/') => (ReplaceMode::All, &body[1..]),
+                Some('#') => (ReplaceMode::Prefix, &body[1..]),
+                Some('%') => (ReplaceMode::Suffix, &body[1..]),
+                _ => (ReplaceMode::First, body),
+            };
+            let (pattern, replacement) = split_unquoted(body, '/');
+            Operation::Replace { mode, pattern, replacement }
+        }
+        Some(op @ ('^' | ',')) => {
+            let upper = op == '^';
+            let doubled = if upper { "^^" } else { ",," };
+            match rest.strip_prefix(doubled) {
+                Some(pattern) => Operation::Case { upper, all: true, pattern },
+                None => Operation::Case { upper, all: false, pattern: &rest[1..] },
+            }
+        }
+        Some(_) => return None,
+    };
+    Some((name, operation))
+}
+
+// The parameter name at the start of `text`: a variable name, digits or a special parameter
+fn parameter_name(text: &str) -> Option<&str> {
+    if text.starts_with(['?', '$', '!', '#', '@', '*', '-']) {
+        return Some(&text[..1]);
+    }
+    if text.starts_with(|c: char| c.is_ascii_digit()) {
+        let end = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+        return Some(&text[..end]);
+    }
+    let end = text
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(text.len());
+    let name = &text[..end];
+    if name.is_empty() || is_valid_name(name) {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+// Split at the first `separator` that is not quoted or escaped
+fn split_unquoted(text: &str, separator: char) -> (&str, &str) {
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            _ if escaped => escaped = false,
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('\''), _) => {}
+            (_, '\\') => escaped = true,
+            (None, '\'' | '"') => quote = Some(c),
+            (None, _) if c == separator => return (&text[..i], &text[i + c.len_utf8()..]),
+            _ => {}
+        }
+    }
+    (text, "")
+}
+
+fn replace(value: &str, pattern: &str, replacement: &str, mode: ReplaceMode) -> String {
+    if pattern.is_empty() && matches!(mode, ReplaceMode::First | ReplaceMode::All) {
+        return value.to_string();
+    }
+    let compiled = Pattern::new(pattern);
+    match mode {
+        ReplaceMode::Prefix => match compiled.match_prefix(value, true) {
+            Some(end) => format!("{}{}", replacement, &value[end..]),
+            None => value.to_string(),
+        },
+        ReplaceMode::Suffix => match compiled.match_suffix(value, true) {
+            Some(start) => format!("{}{}", &value[..start], replacement),
+            None => value.to_string(),
+        },
+        ReplaceMode::First | ReplaceMode::All => {
+            let mut result = String::new();
+            let mut start = 0;
+            let mut replaced = false;
+            while start < value.len() {
+                if !replaced || mode == ReplaceMode::All {
+                    // Longest non-empty match starting here
+                    if let Some(end) = compiled.match_prefix(&value[start..], true).filter(|end| *end > 0) {
+                        result.push_str(replacement);
+                        start += end;
+                        replaced = true;
+                        continue;
+                    }
+                }
+                let c = value[start..].chars().next().unwrap_or_default();
+                result.push(c);
+                start += c.len_utf8();
+            }
+            result
+        }
+    }
+}
+
+// `${var:offset:length}` counted in characters, negative values count from the end
+fn substring(value: &str, offset: i64, length: Option<i64>) -> Result<String, String> {
+    let chars: Vec<char> = value.chars().collect();
+    let count = chars.len() as i64;
+    // Saturating, so huge offsets and lengths just run off either end
+    let start = if offset < 0 { count.saturating_add(offset) } else { offset };
+    if start < 0 || start > count {
+        return Ok(String::new());
+    }
+    let end = match length {
+        None => count,
+        Some(length) if length < 0 => {
+            let end = count.saturating_add(length);
+            if end < start {
+                return Err(format!("{}: substring expression < 0", length));
+            }
+            end
+        }
+        Some(length) => start.saturating_add(length).min(count),
+    };
+    Ok(chars[start as usize..end as usize].iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shell_with(vars: &[(&str, &str)]) -> Shell {
+        let mut shell = Shell::new();
+        for (name, value) in vars {
+            shell.vars.set(name, value).unwrap();
+        }
+        shell
+    }
+
+    fn expand(shell: &mut Shell, raw: &str) -> String {
+        expand_word(shell, &Word(raw.to_string())).unwrap()
+    }
+
+    // Each pattern operator gives the same result with and without double quotes
+    fn assert_both(shell: &mut Shell, expansion: &str, expected: &str) {
+        assert_eq!(expand(shell, expansion), expected, "{}", expansion);
+        assert_eq!(expand(shell, &format!("\"{}\"", expansion)), expected, "\"{}\"", expansion);
+    }
+
+    #[test]
+    fn remove_prefix_and_suffix() {
+        let mut shell = shell_with(&[("p", "a/b/c.txt")]);
+        assert_both(&mut shell, "${p#*/}", "b/c.txt");
+        assert_both(&mut shell, "${p##*/}", "c.txt");
+        assert_both(&mut shell, "${p%/*}", "a/b");
+        assert_both(&mut shell, "${p%%/*}", "a");
+        assert_both(&mut shell, "${p%.[a-z]??}", "a/b/c");
+    }
+
+    #[test]
+    fn replace() {
+        let mut shell = shell_with(&[("p", "a/b/c.txt")]);
+        assert_both(&mut shell, "${p/b*/Z}", "a/Z");
+        assert_both(&mut shell, "${p//[ab]/Z}", "Z/Z/c.txt");
+        assert_both(&mut shell, "${p/#a/Z}", "Z/b/c.txt");
+        assert_both(&mut shell, "${p/%t?t/Z}", "a/b/c.Z");
+    }
+
+    #[test]
+    fn case_conversion() {
+        let mut shell = shell_with(&[("x", "abc"), ("y", "ABC")]);
+        assert_both(&mut shell, "${x^}", "Abc");
+        assert_both(&mut shell, "${x^^[ab]}", "ABc");
+        assert_both(&mut shell, "${y,}", "aBC");
+        assert_both(&mut shell, "${y,,[AB]}", "abC");
+    }
+
+    #[test]
+    fn quoted_pattern_characters_match_literally() {
+        let mut shell = shell_with(&[("p", "a/b/c.txt"), ("s", "a*b"), ("q", "*")]);
+        assert_eq!(expand(&mut shell, "\"${p%\"/*\"}\""), "a/b/c.txt");
+        assert_eq!(expand(&mut shell, "${s/\\*/X}"), "aXb");
+        assert_eq!(expand(&mut shell, "\"${s/'*'/X}\""), "aXb");
+        assert_eq!(expand(&mut shell, "${s/'*'/X}"), "aXb");
+        assert_eq!(expand(&mut shell, "${p#$q/}"), "b/c.txt");
+        assert_eq!(expand(&mut shell, "${p#\"$q\"/}"), "a/b/c.txt");
+    }
+
+    #[test]
+    fn defaults_and_alternatives() {
+        let mut shell = shell_with(&[("set", "value"), ("empty", "")]);
+        assert_eq!(expand(&mut shell, "${unset-default}"), "default");
+        assert_eq!(expand(&mut shell, "${empty-default}"), "");
+        assert_eq!(expand(&mut shell, "${empty:-default}"), "default");
+        assert_eq!(expand(&mut shell, "${set:+alt}"), "alt");
+        assert_eq!(expand(&mut shell, "${unset:+alt}"), "");
+        assert_eq!(expand(&mut shell, "\"${unset-*}\""), "*");
+        assert_eq!(expand(&mut shell, "${unset:=assigned}"), "assigned");
+        assert_eq!(shell.vars.get("unset"), Some("assigned"));
+    }
+
+    #[test]
+    fn unset_parameter_error() {
+        let mut shell = Shell::new();
+        match expand_word(&mut shell, &Word("${missing:?no value}".to_string())) {
+            Err(ExpansionError::ParameterUnset(message)) => assert_eq!(message, "missing: no value"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn length_and_substring() {
+        let mut shell = shell_with(&[("v", "hello")]);
+        assert_eq!(expand(&mut shell, "${#v}"), "5");
+        assert_eq!(expand(&mut shell, "${v:1:3}"), "ell");
+        assert_eq!(expand(&mut shell, "${v: -3}"), "llo");
+        assert_eq!(expand(&mut shell, "${v:1:-1}"), "ell");
+        assert_eq!(expand(&mut shell, "${v:1:9223372036854775807}"), "ello");
+        assert_eq!(expand(&mut shell, "${v:9223372036854775807}"), "");
+    }
+
+    #[test]
+    fn variables() {
+        let mut shell = shell_with(&[("name", "world"), ("empty", "")]);
+        assert_eq!(expand(&mut shell, "$name"), "world");
+        assert_eq!(expand(&mut shell, "${name}s"), "worlds");
+        assert_eq!(expand(&mut shell, "hello,$name!"), "hello,world!");
+        assert_eq!(expand(&mut shell, "\"$name $empty$unset.\""), "world .");
+        assert_eq!(expand(&mut shell, "'$name'"), "$name");
+        assert_eq!(expand(&mut shell, "\\$name"), "$name");
+        assert_eq!(expand(&mut shell, "$"), "$");
+        assert_eq!(expand(&mut shell, "$name_x"), "");
+    }
+
+    #[test]
+    fn assignment_values_are_not_split() {
+        let mut shell = shell_with(&[("v", "a  b")]);
+        assert_eq!(expand_assignment(&mut shell, &Word("$v".to_string())).unwrap(), "a  b");
+        assert_eq!(expand_words(&mut shell, &[Word("$v".to_string())]).unwrap(), ["a", "b"]);
+        assert_eq!(expand_words(&mut shell, &[Word("\"$v\"".to_string())]).unwrap(), ["a  b"]);
+    }
+
+    #[test]
+    fn special_parameters() {
+        let mut shell = Shell::new();
+        shell.positional = vec!["a b".to_string(), "c".to_string()];
+        shell.script_name = "script.sh".to_string();
+        shell.last_status = 3;
+        shell.pid = 42;
+        shell.last_background_pid = Some(43);
+        assert_eq!(expand(&mut shell, "$# $1 $2 $3"), "2 a b c ");
+        assert_eq!(expand(&mut shell, "$0 $? $$ $!"), "script.sh 3 42 43");
+        assert_eq!(expand(&mut shell, "${#}"), "2");
+    }
+
+    #[test]
+    fn positional_parameters_as_fields() {
+        let mut shell = Shell::new();
+        shell.positional = vec!["a b".to_string(), "c".to_string()];
+        let words = |shell: &mut Shell, raw: &str| expand_words(shell, &[Word(raw.to_string())]).unwrap();
+        assert_eq!(words(&mut shell, "\"$@\""), ["a b", "c"]);
+        assert_eq!(words(&mut shell, "\"$*\""), ["a b c"]);
+        assert_eq!(words(&mut shell, "$@"), ["a", "b", "c"]);
+        assert_eq!(words(&mut shell, "x\"$@\"y"), ["xa b", "cy"]);
+        shell.vars.set("IFS", ",").unwrap();
+        assert_eq!(words(&mut shell, "\"$*\""), ["a b,c"]);
+        // "$@" with no parameters is no field at all
+        shell.positional.clear();
+        assert!(words(&mut shell, "\"$@\"").is_empty());
+        assert_eq!(words(&mut shell, "\"$*\""), [""]);
+    }
+
+    #[test]
+    fn tilde_prefixes() {
+        let mut shell = shell_with(&[("HOME", "/home/me"), ("PWD", "/here"), ("OLDPWD", "/there")]);
+        assert_eq!(expand(&mut shell, "~"), "/home/me");
+        assert_eq!(expand(&mut shell, "~/src"), "/home/me/src");
+        assert_eq!(expand(&mut shell, "~+/x"), "/here/x");
+        assert_eq!(expand(&mut shell, "~-"), "/there");
+        assert_eq!(expand(&mut shell, "~root"), "/root");
+        assert_eq!(expand(&mut shell, "~no_such_user_here"), "~no_such_user_here");
+        // Only an unquoted tilde at the start of the word
+        assert_eq!(expand(&mut shell, "\"~\" '~' a~ \\~"), "~ ~ a~ ~");
+    }
+
+    #[test]
+    fn tilde_in_assignments() {
+        let mut shell = shell_with(&[("HOME", "/home/me")]);
+        let value = expand_assignment(&mut shell, &Word("~/bin:~/sbin:/usr/bin".to_string())).unwrap();
+        assert_eq!(value, "/home/me/bin:/home/me/sbin:/usr/bin");
+        assert_eq!(expand(&mut shell, "a:~/bin"), "a:~/bin");
+    }
+
+    fn fields(shell: &mut Shell, raw: &str) -> Vec<String> {
+        expand_words(shell, &[Word(raw.to_string())]).unwrap()
+    }
+
+    #[test]
+    fn field_splitting() {
+        let mut shell = shell_with(&[("v", "  a  b\tc  "), ("csv", "a,,b,"), ("none", "")]);
+        assert_eq!(fields(&mut shell, "$v"), ["a", "b", "c"]);
+        assert_eq!(fields(&mut shell, "x${v}y"), ["x", "a", "b", "c", "y"]);
+        assert!(fields(&mut shell, "$none").is_empty());
+        assert_eq!(fields(&mut shell, "\"$none\""), [""]);
+        shell.vars.set("IFS", ",").unwrap();
+        assert_eq!(fields(&mut shell, "$csv"), ["a", "", "b"]);
+        shell.vars.set("IFS", " :").unwrap();
+        shell.vars.set("path", "a : b::c").unwrap();
+        assert_eq!(fields(&mut shell, "$path"), ["a", "b", "", "c"]);
+        // An empty IFS turns splitting off
+        shell.vars.set("IFS", "").unwrap();
+        assert_eq!(fields(&mut shell, "$v"), ["  a  b\tc  "]);
+    }
+
+    #[test]
+    fn ansi_c_quoting() {
+        let mut shell = Shell::new();
+        assert_eq!(expand(&mut shell, "$'a\\tb\\nc'"), "a\tb\nc");
+        assert_eq!(expand(&mut shell, "$'\\x41\\101\\u263a'"), "AA\u{263a}");
+        assert_eq!(expand(&mut shell, "$'it\\'s' $'\\e' $'\\cA'"), "it's \u{1b} \u{1}");
+        assert_eq!(expand(&mut shell, "\"$'x'\""), "$'x'");
+        assert_eq!(fields(&mut shell, "$'a b'"), ["a b"]);
+    }
+}
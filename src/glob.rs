@@ -0,0 +1,124 @@
+use std::fs;
+use std::path::Path;
+use crate::pattern::{has_wildcards, unescape, Pattern};
+
+// Options changed with `shopt -s` / `shopt -u`
+#[derive(Debug, Clone, Default)]
+pub struct GlobOptions {
+    // Patterns that match nothing disappear instead of staying literal
+    pub nullglob: bool,
+    // Patterns that match nothing make the command fail
+    pub failglob: bool,
+    // `*` and friends also match names starting with `.`
+    pub dotglob: bool,
+    pub nocaseglob: bool,
+    // `**` on its own matches any number of directories
+    pub globstar: bool,
+}
+
+impl GlobOptions {
+    pub const NAMES: &'static [&'static str] = &["dotglob", "failglob", "globstar", "nocaseglob", "nullglob"];
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "dotglob" => Some(&mut self.dotglob),
+            "failglob" => Some(&mut self.failglob),
+            "globstar" => Some(&mut self.globstar),
+            "nocaseglob" => Some(&mut self.nocaseglob),
+            "nullglob" => Some(&mut self.nullglob),
+            _ => None,
+        }
+    }
+}
+
+// Paths matching an escaped pattern, sorted; empty when nothing matches
+pub fn expand(pattern: &str, options: &GlobOptions) -> Vec<String> {
+    let components: Vec<&str> = pattern.split('/').filter(|component| !component.is_empty()).collect();
+    // `*/` only matches directories and keeps the slash
+    let trailing_slash = pattern.ends_with('/');
+    let mut paths = vec![if pattern.starts_with('/') { "/".to_string() } else { String::new() }];
+
+    for (i, component) in components.iter().enumerate() {
+        let last = i == components.len() - 1 && !trailing_slash;
+        let mut next = Vec::new();
+        for base in &paths {
+            if *component == "**" && options.globstar {
+                if !last {
+                    next.push(base.clone());
+                }
+                walk(base, last, options, &mut next);
+            } else if !has_wildcards(component) {
+                let path = format!("{}{}", base, unescape(component));
+                if last && fs::symlink_metadata(&path).is_ok() {
+                    next.push(path);
+                } else if !last && Path::new(&path).is_dir() {
+                    next.push(path + "/");
+                }
+            } else {
+                match_directory(base, component, last, options, &mut next);
+            }
+        }
+        paths = next;
+        if paths.is_empty() {
+            break;
+        }
+    }
+    if components.is_empty() {
+        return Vec::new();
+    }
+    paths.sort();
+    paths
+}
+
+// Entries of the directory `base` whose names match one pattern component
+fn match_directory(base: &str, component: &str, last: bool, options: &GlobOptions, matches: &mut Vec<String>) {
+    let mut pattern = Pattern::new(component);
+    if options.nocaseglob {
+        pattern = pattern.ignore_case();
+    }
+    // A leading `.` has to be matched explicitly unless dotglob is set
+    let explicit_dot = component.starts_with('.') || component.starts_with("\\.");
+    for name in read_names(base) {
+        if name.starts_with('.') && !explicit_dot && !options.dotglob {
+            continue;
+        }
+        if !pattern.matches(&name) {
+            continue;
+        }
+        let path = format!("{}{}", base, name);
+        if last {
+            matches.push(path);
+        } else if Path::new(&path).is_dir() {
+            matches.push(path + "/");
+        }
+    }
+}
+
+// Everything below `base` for `**`: directories only, or files too when it is the last component
+fn walk(base: &str, include_files: bool, options: &GlobOptions, matches: &mut Vec<String>) {
+    for name in read_names(base) {
+        if name.starts_with('.') && !options.dotglob {
+            continue;
+        }
+        let path = format!("{}{}", base, name);
+        // Symlinked directories are listed but not followed
+        let is_dir = fs::symlink_metadata(&path).is_ok_and(|meta| meta.is_dir());
+        if is_dir {
+            matches.push(if include_files { path.clone() } else { format!("{}/", path) });
+            walk(&format!("{}/", path), include_files, options, matches);
+        } else if include_files {
+            matches.push(path);
+        }
+    }
+}
+
+fn read_names(base: &str) -> Vec<String> {
+    let dir = if base.is_empty() { "." } else { base };
+    match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
@@ -0,0 +1,655 @@
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    // Raw word text, quotes and escapes are kept so expansion can see them
+    Word(String),
+    // Digits directly in front of a redirection operator, e.g. the `2` in `2>`
+    IoNumber(i32),
+    Op(Operator),
+    Newline,
+    // Body of a here-document, standing in for its delimiter word
+    HereDoc { body: String, expand: bool },
+    // Expression of a `((...))` arithmetic command
+    Arith(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Pipe,
+    OrIf,
+    Amp,
+    AndIf,
+    Semi,
+    DSemi,
+    SemiAmp,
+    DSemiAmp,
+    LParen,
+    RParen,
+    Less,
+    Great,
+    DGreat,
+    DLess,
+    DLessDash,
+    TLess,
+    LessGreat,
+    GreatAnd,
+    LessAnd,
+    Clobber,
+    AndGreat,
+    AndDGreat,
+}
+
+// Longest operators first so that `>>` wins over `>`
+const OPERATORS: &[(&str, Operator)] = &[
+    (";;&", Operator::DSemiAmp),
+    ("<<-", Operator::DLessDash),
+    ("<<<", Operator::TLess),
+    ("&>>", Operator::AndDGreat),
+    ("&&", Operator::AndIf),
+    ("||", Operator::OrIf),
+    (";;", Operator::DSemi),
+    (";&", Operator::SemiAmp),
+    ("<<", Operator::DLess),
+    (">>", Operator::DGreat),
+    ("<>", Operator::LessGreat),
+    (">&", Operator::GreatAnd),
+    ("<&", Operator::LessAnd),
+    (">|", Operator::Clobber),
+    ("&>", Operator::AndGreat),
+    ("|", Operator::Pipe),
+    ("&", Operator::Amp),
+    (";", Operator::Semi),
+    ("<", Operator::Less),
+    (">", Operator::Great),
+    ("(", Operator::LParen),
+    (")", Operator::RParen),
+];
+
+impl Operator {
+    pub fn as_str(&self) -> &'static str {
+        OPERATORS
+            .iter()
+            .find(|(_, op)| op == self)
+            .map(|(text, _)| *text)
+            .unwrap_or("")
+    }
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum LexError {
+    #[error("unexpected EOF while looking for matching `{0}'")]
+    Unterminated(char),
+    #[error("here-document delimited by end-of-file (wanted `{0}')")]
+    UnterminatedHereDoc(String),
+}
+
+// A here-document whose delimiter has been read but not its body yet
+struct PendingHereDoc {
+    token_index: usize,
+    strip_tabs: bool,
+}
+
+pub fn is_metachar(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\n' | '|' | '&' | ';' | '<' | '>' | '(' | ')')
+}
+
+pub struct Lexer {
+    chars: Vec<char>,
+    pos: usize,
+    pending_heredocs: Vec<PendingHereDoc>,
+    // The input ended right after an unquoted backslash
+    escaped_end: bool,
+}
+
+// A final backslash escapes the newline still to come, so the command goes on
+pub fn ends_with_continuation(input: &str) -> bool {
+    let mut lexer = Lexer::new(input);
+    while let Ok(Some(_)) = lexer.next_token() {}
+    lexer.escaped_end
+}
+
+impl Lexer {
+    pub fn new(input: &str) -> Self {
+        Lexer {
+            chars: input.chars().collect(),
+            pos: 0,
+            pending_heredocs: Vec::new(),
+            escaped_end: false,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek();
+        if c.is_some() {
+            self.pos += 1;
+        }
+        c
+    }
+
+    fn starts_with(&self, text: &str) -> bool {
+        text.chars().enumerate().all(|(i, c)| self.peek_at(i) == Some(c))
+    }
+
+    pub fn tokenize(mut self) -> Result<Vec<Token>, LexError> {
+        let mut tokens = Vec::new();
+        // Set right after `<<` or `<<-`, the next word is the delimiter
+        let mut heredoc_op: Option<bool> = None;
+        while let Some(token) = self.next_token()? {
+            match &token {
+                Token::Op(Operator::DLess) => heredoc_op = Some(false),
+                Token::Op(Operator::DLessDash) => heredoc_op = Some(true),
+                Token::Word(_) => {
+                    if let Some(strip_tabs) = heredoc_op.take() {
+                        self.pending_heredocs.push(PendingHereDoc {
+                            token_index: tokens.len(),
+                            strip_tabs,
+                        });
+                    }
+                }
+                Token::Newline => self.read_heredoc_bodies(&mut tokens)?,
+                _ => heredoc_op = None,
+            }
+            tokens.push(token);
+        }
+        if let Some(pending) = self.pending_heredocs.first() {
+            if let Token::Word(delimiter) = &tokens[pending.token_index] {
+                return Err(LexError::UnterminatedHereDoc(remove_quotes(delimiter)));
+            }
+        }
+        Ok(tokens)
+    }
+
+    // Bodies start on the line after the operator, in the order the operators appeared
+    fn read_heredoc_bodies(&mut self, tokens: &mut [Token]) -> Result<(), LexError> {
+        for pending in std::mem::take(&mut self.pending_heredocs) {
+            let raw_delimiter = match &tokens[pending.token_index] {
+                Token::Word(word) => word.clone(),
+                _ => continue,
+            };
+            let delimiter = remove_quotes(&raw_delimiter);
+            let mut body = String::new();
+            loop {
+                if self.peek().is_none() {
+                    return Err(LexError::UnterminatedHereDoc(delimiter));
+                }
+                let mut line = String::new();
+                while let Some(c) = self.bump() {
+                    if c == '\n' {
+                        break;
+                    }
+                    line.push(c);
+                }
+                if pending.strip_tabs {
+                    line = line.trim_start_matches('\t').to_string();
+                }
+                if line == delimiter {
+                    break;
+                }
+                body.push_str(&line);
+                body.push('\n');
+            }
+            // Quoting any part of the delimiter turns expansion off for the body
+            let expand = !raw_delimiter.contains(['\'', '"', '\\']);
+            tokens[pending.token_index] = Token::HereDoc { body, expand };
+        }
+        Ok(())
+    }
+
+    fn next_token(&mut self) -> Result<Option<Token>, LexError> {
+        // Skip blanks and line continuations between tokens
+        loop {
+            match self.peek() {
+                Some(' ' | '\t') => {
+                    self.pos += 1;
+                }
+                Some('\\') if self.peek_at(1) == Some('\n') => {
+                    self.pos += 2;
+                }
+                Some('#') => {
+                    // Comment runs to the end of the line
+                    while !matches!(self.peek(), None | Some('\n')) {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+
+        let c = match self.peek() {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+
+        if c == '\n' {
+            self.pos += 1;
+            return Ok(Some(Token::Newline));
+        }
+
+        if self.starts_with("((") {
+            if let Some(expression) = self.read_arithmetic_command() {
+                return Ok(Some(Token::Arith(expression)));
+            }
+        }
+
+        for (text, op) in OPERATORS {
+            if self.starts_with(text) {
+                self.pos += text.chars().count();
+                return Ok(Some(Token::Op(*op)));
+            }
+        }
+
+        let word = self.read_word()?;
+        if !word.is_empty()
+            && word.chars().all(|c| c.is_ascii_digit())
+            && matches!(self.peek(), Some('<' | '>'))
+        {
+            if let Ok(fd) = word.parse::<i32>() {
+                return Ok(Some(Token::IoNumber(fd)));
+            }
+        }
+        Ok(Some(Token::Word(word)))
+    }
+
+    // `((expr))`, otherwise `((` is two subshell parentheses and None is returned
+    fn read_arithmetic_command(&mut self) -> Option<String> {
+        let start = self.pos + 2;
+        let mut depth = 0;
+        let mut i = start;
+        while let Some(&c) = self.chars.get(i) {
+            match c {
+                '(' => depth += 1,
+                ')' if depth == 0 => {
+                    if self.chars.get(i + 1) != Some(&')') {
+                        return None;
+                    }
+                    self.pos = i + 2;
+                    return Some(self.chars[start..i].iter().collect());
+                }
+                ')' => depth -= 1,
+                _ => {}
+            }
+            i += 1;
+        }
+        None
+    }
+
+    fn read_word(&mut self) -> Result<String, LexError> {
+        let mut word = String::new();
+        while let Some(c) = self.peek() {
+            if is_metachar(c) {
+                break;
+            }
+            match c {
+                '\\' => {
+                    self.pos += 1;
+                    match self.bump() {
+                        // Backslash-newline joins the two lines
+                        Some('\n') => {}
+                        Some(next) => {
+                            word.push('\\');
+                            word.push(next);
+                        }
+                        // Like a continuation onto a line that never comes
+                        None => self.escaped_end = true,
+                    }
+                }
+                '\'' => self.read_single_quoted(&mut word)?,
+                '"' => self.read_double_quoted(&mut word)?,
+                '`' => self.read_backquoted(&mut word)?,
+                '$' if self.peek_at(1) == Some('\'') => {
+                    word.push('$');
+                    self.pos += 1;
+                    self.read_ansi_c_quoted(&mut word)?;
+                }
+                '$' if self.peek_at(1) == Some('{') => {
+                    word.push('$');
+                    self.pos += 1;
+                    self.read_braced(&mut word)?;
+                }
+                '$' if self.peek_at(1) == Some('(') => {
+                    word.push('$');
+                    self.pos += 1;
+                    self.read_parenthesized(&mut word)?;
+                }
+                _ => {
+                    word.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+        Ok(word)
+    }
+
+    // `${...}`, which may hold quotes, blanks and nested expansions
+    fn read_braced(&mut self, word: &mut String) -> Result<(), LexError> {
+        word.push('{');
+        self.pos += 1;
+        loop {
+            match self.peek() {
+                Some('}') => {
+                    word.push('}');
+                    self.pos += 1;
+                    return Ok(());
+                }
+                Some('\\') => {
+                    self.pos += 1;
+                    word.push('\\');
+                    if let Some(next) = self.bump() {
+                        word.push(next);
+                    }
+                }
+                Some('\'') => self.read_single_quoted(word)?,
+                Some('"') => self.read_double_quoted(word)?,
+                Some('`') => self.read_backquoted(word)?,
+                Some('$') if self.peek_at(1) == Some('{') => {
+                    word.push('$');
+                    self.pos += 1;
+                    self.read_braced(word)?;
+                }
+                Some('$') if self.peek_at(1) == Some('(') => {
+                    word.push('$');
+                    self.pos += 1;
+                    self.read_parenthesized(word)?;
+                }
+                Some(c) => {
+                    word.push(c);
+                    self.pos += 1;
+                }
+                None => return Err(LexError::Unterminated('}')),
+            }
+        }
+    }
+
+    // `$(...)`, kept whole including nested parentheses, quotes and substitutions
+    fn read_parenthesized(&mut self, word: &mut String) -> Result<(), LexError> {
+        word.push('(');
+        self.pos += 1;
+        let mut depth = 1;
+        loop {
+            match self.peek() {
+                Some('(') => {
+                    depth += 1;
+                    word.push('(');
+                    self.pos += 1;
+                }
+                Some(')') => {
+                    depth -= 1;
+                    word.push(')');
+                    self.pos += 1;
+                    if depth == 0 {
+                        return Ok(());
+                    }
+                }
+                Some('\\') => {
+                    self.pos += 1;
+                    word.push('\\');
+                    if let Some(next) = self.bump() {
+                        word.push(next);
+                    }
+                }
+                Some('\'') => self.read_single_quoted(word)?,
+                Some('"') => self.read_double_quoted(word)?,
+                Some('`') => self.read_backquoted(word)?,
+                Some('$') if self.peek_at(1) == Some('{') => {
+                    word.push('$');
+                    self.pos += 1;
+                    self.read_braced(word)?;
+                }
+                Some(c) => {
+                    word.push(c);
+                    self.pos += 1;
+                }
+                None => return Err(LexError::Unterminated(')')),
+            }
+        }
+    }
+
+    // Legacy `...` substitution, which ends at the first unescaped backquote
+    fn read_backquoted(&mut self, word: &mut String) -> Result<(), LexError> {
+        word.push('`');
+        self.pos += 1;
+        loop {
+            match self.bump() {
+                Some('`') => {
+                    word.push('`');
+                    return Ok(());
+                }
+                Some('\\') => {
+                    word.push('\\');
+                    match self.bump() {
+                        Some(next) => word.push(next),
+                        None => return Err(LexError::Unterminated('`')),
+                    }
+                }
+                Some(c) => word.push(c),
+                None => return Err(LexError::Unterminated('`')),
+            }
+        }
+    }
+
+    fn read_single_quoted(&mut self, word: &mut String) -> Result<(), LexError> {
+        word.push('\'');
+        self.pos += 1;
+        loop {
+            match self.bump() {
+                Some('\'') => {
+                    word.push('\'');
+                    return Ok(());
+                }
+                Some(c) => word.push(c),
+                None => return Err(LexError::Unterminated('\'')),
+            }
+        }
+    }
+
+    // `$'...'` after the `$`, where `\'` does not end the quotes
+    fn read_ansi_c_quoted(&mut self, word: &mut String) -> Result<(), LexError> {
+        word.push('\'');
+        self.pos += 1;
+        loop {
+            match self.bump() {
+                Some('\'') => {
+                    word.push('\'');
+                    return Ok(());
+                }
+                Some('\\') => {
+                    word.push('\\');
+                    match self.bump() {
+                        Some(next) => word.push(next),
+                        None => return Err(LexError::Unterminated('\'')),
+                    }
+                }
+                Some(c) => word.push(c),
+                None => return Err(LexError::Unterminated('\'')),
+            }
+        }
+    }
+
+    fn read_double_quoted(&mut self, word: &mut String) -> Result<(), LexError> {
+        word.push('"');
+        self.pos += 1;
+        loop {
+            match self.bump() {
+                Some('"') => {
+                    word.push('"');
+                    return Ok(());
+                }
+                Some('\\') => match self.bump() {
+                    Some('\n') => {}
+                    Some(next) => {
+                        word.push('\\');
+                        word.push(next);
+                    }
+                    None => return Err(LexError::Unterminated('"')),
+                },
+                Some('$') if self.peek() == Some('{') => {
+                    word.push('$');
+                    self.read_braced(word)?;
+                }
+                Some('$') if self.peek() == Some('(') => {
+                    word.push('$');
+                    self.read_parenthesized(word)?;
+                }
+                Some('`') => {
+                    self.pos -= 1;
+                    self.read_backquoted(word)?;
+                }
+                Some(c) => word.push(c),
+                None => return Err(LexError::Unterminated('"')),
+            }
+        }
+    }
+}
+
+// Quote removal for a here-document delimiter
+fn remove_quotes(raw: &str) -> String {
+    let mut result = String::new();
+    let mut chars = raw.chars();
+    let mut quote: Option<char> = None;
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (None, '\'' | '"') => quote = Some(c),
+            (Some(open), _) if c == open => quote = None,
+            (None, '\\') | (Some('"'), '\\') => {
+                if let Some(next) = chars.next() {
+                    result.push(next);
+                }
+            }
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(input: &str) -> Vec<Token> {
+        Lexer::new(input).tokenize().unwrap()
+    }
+
+    fn word(text: &str) -> Token {
+        Token::Word(text.to_string())
+    }
+
+    #[test]
+    fn words_keep_their_quotes() {
+        assert_eq!(
+            tokens(r#"echo 'a b' "c $d" e\ f"#),
+            [word("echo"), word("'a b'"), word("\"c $d\""), word("e\\ f")]
+        );
+        assert_eq!(tokens("a'b'\"c\"d"), [word("a'b'\"c\"d")]);
+    }
+
+    #[test]
+    fn operators_split_words() {
+        assert_eq!(
+            tokens("a|b&&c;d>>e"),
+            [
+                word("a"),
+                Token::Op(Operator::Pipe),
+                word("b"),
+                Token::Op(Operator::AndIf),
+                word("c"),
+                Token::Op(Operator::Semi),
+                word("d"),
+                Token::Op(Operator::DGreat),
+                word("e"),
+            ]
+        );
+        // Quoted operators are just text
+        assert_eq!(tokens(r"echo '|' \;"), [word("echo"), word("'|'"), word(r"\;")]);
+    }
+
+    #[test]
+    fn io_numbers_only_touch_redirections() {
+        assert_eq!(
+            tokens("cmd 2>err 2 >out"),
+            [
+                word("cmd"),
+                Token::IoNumber(2),
+                Token::Op(Operator::Great),
+                word("err"),
+                word("2"),
+                Token::Op(Operator::Great),
+                word("out"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unterminated_quotes() {
+        assert_eq!(Lexer::new("echo 'abc").tokenize(), Err(LexError::Unterminated('\'')));
+        assert_eq!(Lexer::new("echo \"abc").tokenize(), Err(LexError::Unterminated('"')));
+    }
+
+    #[test]
+    fn here_document_bodies() {
+        assert_eq!(
+            tokens("cat <<EOF\nhello $x\nEOF\necho done"),
+            [
+                word("cat"),
+                Token::Op(Operator::DLess),
+                Token::HereDoc { body: "hello $x\n".to_string(), expand: true },
+                Token::Newline,
+                word("echo"),
+                word("done"),
+            ]
+        );
+        // A quoted delimiter turns off expansion in the body
+        assert_eq!(
+            tokens("cat <<'EOF'\n$x\nEOF")[2],
+            Token::HereDoc { body: "$x\n".to_string(), expand: false }
+        );
+        assert_eq!(
+            tokens("cat <<\"E\"OF\n$x\nEOF")[2],
+            Token::HereDoc { body: "$x\n".to_string(), expand: false }
+        );
+    }
+
+    #[test]
+    fn here_document_strips_tabs() {
+        assert_eq!(
+            tokens("cat <<-EOF\n\tone\n\t\ttwo\n\tEOF\n")[2],
+            Token::HereDoc { body: "one\ntwo\n".to_string(), expand: true }
+        );
+    }
+
+    #[test]
+    fn here_documents_in_order() {
+        let tokens = tokens("cat <<A; cat <<B\nfirst\nA\nsecond\nB\n");
+        let bodies: Vec<&Token> = tokens.iter().filter(|token| matches!(token, Token::HereDoc { .. })).collect();
+        assert_eq!(
+            bodies,
+            [
+                &Token::HereDoc { body: "first\n".to_string(), expand: true },
+                &Token::HereDoc { body: "second\n".to_string(), expand: true },
+            ]
+        );
+    }
+
+    #[test]
+    fn unterminated_here_document() {
+        assert_eq!(
+            Lexer::new("cat <<EOF\nbody\n").tokenize(),
+            Err(LexError::UnterminatedHereDoc("EOF".to_string()))
+        );
+    }
+
+    #[test]
+    fn trailing_backslash_continues() {
+        assert!(ends_with_continuation("echo a \\"));
+        assert!(!ends_with_continuation("echo a \\\\"));
+        assert!(!ends_with_continuation("echo 'a \\'"));
+    }
+}
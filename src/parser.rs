@@ -0,0 +1,554 @@
+use thiserror::Error;
+use std::rc::Rc;
+use crate::ast::{
+    AndOr, Assignment, CaseItem, CaseTerminator, Command, CompoundCommand, Connector, List, Pipeline, Redirect,
+    RedirectOp, SimpleCommand, Word,
+};
+use crate::lexer::{ends_with_continuation, LexError, Lexer, Operator, Token};
+use crate::variables::{is_valid_name, split_assignment};
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ParseError {
+    #[error(transparent)]
+    Lex(#[from] LexError),
+    #[error("syntax error near unexpected token `{0}'")]
+    Unexpected(String),
+    #[error("syntax error: unexpected end of file")]
+    UnexpectedEof,
+}
+
+impl ParseError {
+    // Input that could still become valid with more lines
+    pub fn is_incomplete(&self) -> bool {
+        matches!(
+            self,
+            ParseError::Lex(LexError::Unterminated(_) | LexError::UnterminatedHereDoc(_)) | ParseError::UnexpectedEof
+        )
+    }
+}
+
+// Whether an interactive command needs another line: an open quote or
+// compound command, a pending here-document, a trailing `|` or `&&`, or a
+// backslash at the very end
+pub fn needs_more_input(input: &str) -> bool {
+    match parse(input) {
+        Ok(_) => ends_with_continuation(input),
+        Err(e) => e.is_incomplete(),
+    }
+}
+
+pub fn parse(input: &str) -> Result<List, ParseError> {
+    let tokens = Lexer::new(input).tokenize()?;
+    let mut parser = Parser { tokens, pos: 0 };
+    let list = parser.parse_list()?;
+    if let Some(token) = parser.peek() {
+        return Err(ParseError::Unexpected(describe(token)));
+    }
+    Ok(list)
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Word(word) => word.clone(),
+        Token::IoNumber(fd) => fd.to_string(),
+        Token::Op(op) => op.as_str().to_string(),
+        Token::Newline => "newline".to_string(),
+        Token::HereDoc { .. } => "<<".to_string(),
+        Token::Arith(_) => "((".to_string(),
+    }
+}
+
+// Reserved words that end the list before them when they start a command
+const CLOSING_WORDS: &[&str] = &["}", "then", "elif", "else", "fi", "do", "done", "esac"];
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        if token.is_some() {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn peek_op(&self) -> Option<Operator> {
+        match self.peek() {
+            Some(Token::Op(op)) => Some(*op),
+            _ => None,
+        }
+    }
+
+    fn unexpected(&self) -> ParseError {
+        match self.peek() {
+            Some(token) => ParseError::Unexpected(describe(token)),
+            None => ParseError::UnexpectedEof,
+        }
+    }
+
+    fn peek_word(&self) -> Option<&str> {
+        match self.peek() {
+            Some(Token::Word(word)) => Some(word),
+            _ => None,
+        }
+    }
+
+    fn expect_op(&mut self, op: Operator) -> Result<(), ParseError> {
+        if self.peek_op() != Some(op) {
+            return Err(self.unexpected());
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    // Reserved words are ordinary words that mean something at the start of a command
+    fn expect_word(&mut self, word: &str) -> Result<(), ParseError> {
+        if self.peek_word() != Some(word) {
+            return Err(self.unexpected());
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn skip_newlines(&mut self) {
+        while self.peek() == Some(&Token::Newline) {
+            self.pos += 1;
+        }
+    }
+
+    // list: and_or ((';' | '&' | newline) and_or)*
+    fn parse_list(&mut self) -> Result<List, ParseError> {
+        let mut list = List::default();
+        self.skip_newlines();
+        while self.starts_command() {
+            let mut and_or = self.parse_and_or()?;
+            and_or.background = self.peek_op() == Some(Operator::Amp);
+            list.items.push(and_or);
+            match self.peek() {
+                Some(Token::Op(Operator::Semi | Operator::Amp)) | Some(Token::Newline) => {
+                    self.pos += 1;
+                    self.skip_newlines();
+                }
+                _ => break,
+            }
+        }
+        Ok(list)
+    }
+
+    // A list that has to hold at least one command, as inside `{ }` or `( )`
+    fn parse_body(&mut self) -> Result<List, ParseError> {
+        let list = self.parse_list()?;
+        if list.items.is_empty() {
+            return Err(self.unexpected());
+        }
+        Ok(list)
+    }
+
+    fn starts_command(&self) -> bool {
+        match self.peek() {
+            // A closing reserved word ends the list instead
+            Some(Token::Word(word)) => !CLOSING_WORDS.contains(&word.as_str()),
+            Some(Token::IoNumber(_)) => true,
+            Some(Token::Op(Operator::LParen)) | Some(Token::Arith(_)) => true,
+            Some(Token::Op(op)) => redirect_op(*op).is_some(),
+            _ => false,
+        }
+    }
+
+    // and_or: pipeline (('&&' | '||') linebreak pipeline)*
+    fn parse_and_or(&mut self) -> Result<AndOr, ParseError> {
+        let first = self.parse_pipeline()?;
+        let mut rest = Vec::new();
+        loop {
+            let connector = match self.peek_op() {
+                Some(Operator::AndIf) => Connector::And,
+                Some(Operator::OrIf) => Connector::Or,
+                _ => break,
+            };
+            self.pos += 1;
+            self.skip_newlines();
+            rest.push((connector, self.parse_pipeline()?));
+        }
+        Ok(AndOr {
+            first,
+            rest,
+            background: false,
+        })
+    }
+
+    // pipeline: command ('|' linebreak command)*
+    fn parse_pipeline(&mut self) -> Result<Pipeline, ParseError> {
+        let mut commands = vec![self.parse_command()?];
+        while self.peek_op() == Some(Operator::Pipe) {
+            self.pos += 1;
+            self.skip_newlines();
+            commands.push(self.parse_command()?);
+        }
+        Ok(Pipeline { commands })
+    }
+
+    fn parse_command(&mut self) -> Result<Command, ParseError> {
+        if let Some(compound) = self.parse_compound_command()? {
+            let redirects = self.parse_redirects()?;
+            return Ok(Command::Compound(compound, redirects));
+        }
+        // name() compound-command
+        if let Some(Token::Word(name)) = self.peek() {
+            if is_valid_name(name)
+                && self.tokens.get(self.pos + 1) == Some(&Token::Op(Operator::LParen))
+                && self.tokens.get(self.pos + 2) == Some(&Token::Op(Operator::RParen))
+            {
+                let name = name.clone();
+                self.pos += 3;
+                self.skip_newlines();
+                let Some(compound) = self.parse_compound_command()? else {
+                    return Err(self.unexpected());
+                };
+                let redirects = self.parse_redirects()?;
+                return Ok(Command::FunctionDef {
+                    name,
+                    body: Rc::new(Command::Compound(compound, redirects)),
+                });
+            }
+        }
+        Ok(Command::Simple(self.parse_simple_command()?))
+    }
+
+    fn parse_compound_command(&mut self) -> Result<Option<CompoundCommand>, ParseError> {
+        if let Some(Token::Arith(expression)) = self.peek() {
+            let expression = Word(expression.clone());
+            self.pos += 1;
+            return Ok(Some(CompoundCommand::Arithmetic(expression)));
+        }
+        if self.peek_op() == Some(Operator::LParen) {
+            self.pos += 1;
+            let body = self.parse_body()?;
+            self.expect_op(Operator::RParen)?;
+            return Ok(Some(CompoundCommand::Subshell(body)));
+        }
+        match self.peek_word() {
+            Some("{") => {
+                self.pos += 1;
+                let body = self.parse_body()?;
+                self.expect_word("}")?;
+                Ok(Some(CompoundCommand::BraceGroup(body)))
+            }
+            Some("if") => {
+                self.pos += 1;
+                self.parse_if().map(Some)
+            }
+            Some(keyword @ ("while" | "until")) => {
+                let until = keyword == "until";
+                self.pos += 1;
+                let condition = self.parse_body()?;
+                let body = self.parse_do_group()?;
+                Ok(Some(CompoundCommand::While { until, condition, body }))
+            }
+            Some("for") => {
+                self.pos += 1;
+                self.parse_for().map(Some)
+            }
+            Some("case") => {
+                self.pos += 1;
+                self.parse_case().map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // Everything after `if` up to and including `fi`
+    fn parse_if(&mut self) -> Result<CompoundCommand, ParseError> {
+        let mut branches = Vec::new();
+        let mut else_body = None;
+        loop {
+            let condition = self.parse_body()?;
+            self.expect_word("then")?;
+            branches.push((condition, self.parse_body()?));
+            match self.peek_word() {
+                Some("elif") => self.pos += 1,
+                Some("else") => {
+                    self.pos += 1;
+                    else_body = Some(self.parse_body()?);
+                    break;
+                }
+                _ => break,
+            }
+        }
+        self.expect_word("fi")?;
+        Ok(CompoundCommand::If { branches, else_body })
+    }
+
+    // Everything after `for`, in either the word list or the arithmetic form
+    fn parse_for(&mut self) -> Result<CompoundCommand, ParseError> {
+        if let Some(Token::Arith(expression)) = self.peek() {
+            let parts: Vec<&str> = expression.split(';').collect();
+            let [init, test, step] = parts.as_slice() else {
+                return Err(self.unexpected());
+            };
+            let (init, test, step) = (Word(init.to_string()), Word(test.to_string()), Word(step.to_string()));
+            self.pos += 1;
+            if self.peek_op() == Some(Operator::Semi) {
+                self.pos += 1;
+            }
+            self.skip_newlines();
+            let body = self.parse_do_group()?;
+            return Ok(CompoundCommand::ArithFor { init, test, step, body });
+        }
+
+        // The name is checked when the loop runs, as bash does
+        let name = match self.peek_word() {
+            Some(name) => name.to_string(),
+            None => return Err(self.unexpected()),
+        };
+        self.pos += 1;
+        self.skip_newlines();
+        let mut words = None;
+        if self.peek_word() == Some("in") {
+            self.pos += 1;
+            let mut list = Vec::new();
+            while let Some(Token::Word(word)) = self.peek() {
+                list.push(Word(word.clone()));
+                self.pos += 1;
+            }
+            // The words have to be ended by `;` or a newline before `do`
+            match self.peek() {
+                Some(Token::Op(Operator::Semi)) | Some(Token::Newline) => self.pos += 1,
+                _ => return Err(self.unexpected()),
+            }
+            words = Some(list);
+        } else if self.peek_op() == Some(Operator::Semi) {
+            self.pos += 1;
+        }
+        self.skip_newlines();
+        let body = self.parse_do_group()?;
+        Ok(CompoundCommand::For { name, words, body })
+    }
+
+    // Everything after `case` up to and including `esac`
+    fn parse_case(&mut self) -> Result<CompoundCommand, ParseError> {
+        let word = match self.peek_word() {
+            Some(word) => Word(word.to_string()),
+            None => return Err(self.unexpected()),
+        };
+        self.pos += 1;
+        self.skip_newlines();
+        self.expect_word("in")?;
+        self.skip_newlines();
+
+        let mut items = Vec::new();
+        while self.peek_word() != Some("esac") {
+            // An optional `(` before the patterns
+            if self.peek_op() == Some(Operator::LParen) {
+                self.pos += 1;
+            }
+            let mut patterns = Vec::new();
+            loop {
+                match self.peek_word() {
+                    Some(pattern) => patterns.push(Word(pattern.to_string())),
+                    None => return Err(self.unexpected()),
+                }
+                self.pos += 1;
+                if self.peek_op() != Some(Operator::Pipe) {
+                    break;
+                }
+                self.pos += 1;
+            }
+            self.expect_op(Operator::RParen)?;
+            let body = self.parse_list()?;
+            let terminator = match self.peek_op() {
+                Some(Operator::DSemi) => CaseTerminator::Break,
+                Some(Operator::SemiAmp) => CaseTerminator::FallThrough,
+                Some(Operator::DSemiAmp) => CaseTerminator::Continue,
+                // The last item can leave out `;;`
+                _ => {
+                    items.push(CaseItem {
+                        patterns,
+                        body,
+                        terminator: CaseTerminator::Break,
+                    });
+                    break;
+                }
+            };
+            self.pos += 1;
+            self.skip_newlines();
+            items.push(CaseItem {
+                patterns,
+                body,
+                terminator,
+            });
+        }
+        self.expect_word("esac")?;
+        Ok(CompoundCommand::Case { word, items })
+    }
+
+    // do list done, the body of a loop
+    fn parse_do_group(&mut self) -> Result<List, ParseError> {
+        self.expect_word("do")?;
+        let body = self.parse_body()?;
+        self.expect_word("done")?;
+        Ok(body)
+    }
+
+    fn parse_redirects(&mut self) -> Result<Vec<Redirect>, ParseError> {
+        let mut redirects = Vec::new();
+        while let Some(redirect) = self.parse_redirect()? {
+            redirects.push(redirect);
+        }
+        Ok(redirects)
+    }
+
+    fn parse_simple_command(&mut self) -> Result<SimpleCommand, ParseError> {
+        let mut command = SimpleCommand {
+            assignments: Vec::new(),
+            words: Vec::new(),
+            redirects: Vec::new(),
+        };
+        loop {
+            match self.peek() {
+                Some(Token::Word(word)) => {
+                    // Assignments are only recognised before the command name
+                    match split_assignment(word) {
+                        Some((name, value)) if command.words.is_empty() => {
+                            command.assignments.push(Assignment {
+                                name: name.to_string(),
+                                value: Word(value.to_string()),
+                            });
+                        }
+                        _ => command.words.push(Word(word.clone())),
+                    }
+                    self.pos += 1;
+                }
+                Some(Token::IoNumber(_)) | Some(Token::Op(_)) => match self.parse_redirect()? {
+                    Some(redirect) => command.redirects.push(redirect),
+                    None => break,
+                },
+                _ => break,
+            }
+        }
+        if command.assignments.is_empty() && command.words.is_empty() && command.redirects.is_empty() {
+            return Err(self.unexpected());
+        }
+        Ok(command)
+    }
+
+    fn parse_redirect(&mut self) -> Result<Option<Redirect>, ParseError> {
+        let start = self.pos;
+        let fd = match self.peek() {
+            Some(Token::IoNumber(fd)) => {
+                let fd = *fd;
+                self.pos += 1;
+                Some(fd)
+            }
+            _ => None,
+        };
+        let op = match self.peek_op().and_then(redirect_op) {
+            Some(op) => op,
+            None if fd.is_some() => return Err(self.unexpected()),
+            None => {
+                self.pos = start;
+                return Ok(None);
+            }
+        };
+        self.pos += 1;
+        match self.next() {
+            Some(Token::HereDoc { body, expand }) => Ok(Some(Redirect {
+                fd,
+                op: RedirectOp::HereDoc { expand },
+                target: Word(body),
+            })),
+            Some(Token::Word(target)) if !matches!(op, RedirectOp::HereDoc { .. }) => Ok(Some(Redirect {
+                fd,
+                op,
+                target: Word(target),
+            })),
+            Some(token) => Err(ParseError::Unexpected(describe(&token))),
+            None => Err(ParseError::Unexpected("newline".to_string())),
+        }
+    }
+}
+
+fn redirect_op(op: Operator) -> Option<RedirectOp> {
+    match op {
+        Operator::Great => Some(RedirectOp::Output),
+        Operator::DGreat => Some(RedirectOp::Append),
+        Operator::Less => Some(RedirectOp::Input),
+        Operator::LessGreat => Some(RedirectOp::ReadWrite),
+        Operator::Clobber => Some(RedirectOp::Clobber),
+        Operator::GreatAnd => Some(RedirectOp::DupOutput),
+        Operator::LessAnd => Some(RedirectOp::DupInput),
+        Operator::AndGreat => Some(RedirectOp::OutputBoth),
+        Operator::AndDGreat => Some(RedirectOp::AppendBoth),
+        Operator::DLess | Operator::DLessDash => Some(RedirectOp::HereDoc { expand: true }),
+        Operator::TLess => Some(RedirectOp::HereString),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redirects(input: &str) -> Vec<Redirect> {
+        let list = parse(input).unwrap();
+        match &list.items[0].first.commands[0] {
+            Command::Simple(command) => command.redirects.clone(),
+            command => panic!("not a simple command: {:?}", command),
+        }
+    }
+
+    fn redirect(fd: Option<i32>, op: RedirectOp, target: &str) -> Redirect {
+        Redirect {
+            fd,
+            op,
+            target: Word(target.to_string()),
+        }
+    }
+
+    #[test]
+    fn redirects_keep_their_order() {
+        assert_eq!(
+            redirects("cmd 2>&1 >f"),
+            [
+                redirect(Some(2), RedirectOp::DupOutput, "1"),
+                redirect(None, RedirectOp::Output, "f"),
+            ]
+        );
+        assert_eq!(
+            redirects("cmd >f 2>&1"),
+            [
+                redirect(None, RedirectOp::Output, "f"),
+                redirect(Some(2), RedirectOp::DupOutput, "1"),
+            ]
+        );
+    }
+
+    #[test]
+    fn duplication_and_combined_redirects() {
+        assert_eq!(
+            redirects("cmd 3<&0 4>&- &>all &>>more"),
+            [
+                redirect(Some(3), RedirectOp::DupInput, "0"),
+                redirect(Some(4), RedirectOp::DupOutput, "-"),
+                redirect(None, RedirectOp::OutputBoth, "all"),
+                redirect(None, RedirectOp::AppendBoth, "more"),
+            ]
+        );
+    }
+
+    #[test]
+    fn incomplete_input_needs_more() {
+        for input in ["echo 'a", "echo \"a", "if true; then", "for i in 1; do echo", "{ echo", "ls |", "a &&", "echo \\", "cat <<EOF\nbody"] {
+            assert!(needs_more_input(input), "{:?}", input);
+        }
+        for input in ["echo done", "echo 'a\nb'", "if true; then :; fi", "cat <<EOF\nbody\nEOF", "echo \\\\", "echo )"] {
+            assert!(!needs_more_input(input), "{:?}", input);
+        }
+    }
+}
@@ -0,0 +1,288 @@
+// Shell pattern matching: `*`, `?`, `[...]` and backslash escapes
+
+#[derive(Debug, Clone, PartialEq)]
+enum ClassItem {
+    Char(char),
+    Range(char, char),
+    Named(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Char(char),
+    // ?
+    Any,
+    // *
+    Star,
+    // [...] or [!...]
+    Class { negated: bool, items: Vec<ClassItem> },
+}
+
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    tokens: Vec<Token>,
+    ignore_case: bool,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Self {
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut tokens = Vec::new();
+        let mut i = 0;
+        while i < chars.len() {
+            match chars[i] {
+                '\\' if i + 1 < chars.len() => {
+                    tokens.push(Token::Char(chars[i + 1]));
+                    i += 2;
+                    continue;
+                }
+                '*' => {
+                    // Consecutive stars behave like one
+                    if tokens.last() != Some(&Token::Star) {
+                        tokens.push(Token::Star);
+                    }
+                }
+                '?' => tokens.push(Token::Any),
+                '[' => {
+                    if let Some((class, end)) = parse_class(&chars, i) {
+                        tokens.push(class);
+                        i = end;
+                        continue;
+                    }
+                    // No closing bracket, so it is an ordinary character
+                    tokens.push(Token::Char('['));
+                }
+                c => tokens.push(Token::Char(c)),
+            }
+            i += 1;
+        }
+        Pattern {
+            tokens,
+            ignore_case: false,
+        }
+    }
+
+    pub fn ignore_case(mut self) -> Self {
+        self.ignore_case = true;
+        self
+    }
+
+    fn token_matches(&self, token: &Token, c: char) -> bool {
+        if token_matches(token, c) {
+            return true;
+        }
+        self.ignore_case
+            && (c.to_lowercase().any(|lower| token_matches(token, lower))
+                || c.to_uppercase().any(|upper| token_matches(token, upper)))
+    }
+
+    // The whole of `text` has to match
+    pub fn matches(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        self.matches_chars(&text)
+    }
+
+    fn matches_chars(&self, text: &[char]) -> bool {
+        let tokens = &self.tokens;
+        let (mut p, mut t) = (0, 0);
+        // Where to resume after the most recent `*` if the rest fails
+        let mut backtrack: Option<(usize, usize)> = None;
+        while t < text.len() {
+            match tokens.get(p) {
+                Some(Token::Star) => {
+                    backtrack = Some((p + 1, t));
+                    p += 1;
+                }
+                Some(token) if self.token_matches(token, text[t]) => {
+                    p += 1;
+                    t += 1;
+                }
+                _ => match backtrack {
+                    Some((star_p, star_t)) => {
+                        p = star_p;
+                        t = star_t + 1;
+                        backtrack = Some((star_p, star_t + 1));
+                    }
+                    None => return false,
+                },
+            }
+        }
+        tokens[p..].iter().all(|token| *token == Token::Star)
+    }
+
+    // Byte length of the shortest or longest prefix of `text` that matches
+    pub fn match_prefix(&self, text: &str, longest: bool) -> Option<usize> {
+        let bounds = char_boundaries(text);
+        let mut candidates: Box<dyn Iterator<Item = &usize>> = if longest {
+            Box::new(bounds.iter().rev())
+        } else {
+            Box::new(bounds.iter())
+        };
+        candidates.find(|end| self.matches(&text[..**end])).copied()
+    }
+
+    // Byte offset where the shortest or longest matching suffix of `text` starts
+    pub fn match_suffix(&self, text: &str, longest: bool) -> Option<usize> {
+        let bounds = char_boundaries(text);
+        let mut candidates: Box<dyn Iterator<Item = &usize>> = if longest {
+            Box::new(bounds.iter())
+        } else {
+            Box::new(bounds.iter().rev())
+        };
+        candidates.find(|start| self.matches(&text[**start..])).copied()
+    }
+}
+
+// Every char boundary in `text`, including both ends
+fn char_boundaries(text: &str) -> Vec<usize> {
+    text.char_indices().map(|(i, _)| i).chain(std::iter::once(text.len())).collect()
+}
+
+// Does the string contain an unescaped `*`, `?` or `[...]`
+pub fn has_wildcards(pattern: &str) -> bool {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            '*' | '?' => return true,
+            '[' if parse_class(&chars, i).is_some() => return true,
+            _ => {}
+        }
+        i += 1;
+    }
+    false
+}
+
+// Drop the backslashes from an escaped pattern that has no wildcards
+pub fn unescape(pattern: &str) -> String {
+    let mut text = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => text.extend(chars.next()),
+            _ => text.push(c),
+        }
+    }
+    text
+}
+
+fn token_matches(token: &Token, c: char) -> bool {
+    match token {
+        Token::Char(expected) => *expected == c,
+        Token::Any => true,
+        Token::Star => false,
+        Token::Class { negated, items } => {
+            let found = items.iter().any(|item| match item {
+                ClassItem::Char(expected) => *expected == c,
+                ClassItem::Range(low, high) => (*low..=*high).contains(&c),
+                ClassItem::Named(name) => class_contains(name, c),
+            });
+            found != *negated
+        }
+    }
+}
+
+fn class_contains(name: &str, c: char) -> bool {
+    match name {
+        "alpha" => c.is_alphabetic(),
+        "digit" => c.is_ascii_digit(),
+        "alnum" => c.is_alphanumeric(),
+        "upper" => c.is_uppercase(),
+        "lower" => c.is_lowercase(),
+        "space" => c.is_whitespace(),
+        "blank" => c == ' ' || c == '\t',
+        "punct" => c.is_ascii_punctuation(),
+        "xdigit" => c.is_ascii_hexdigit(),
+        "cntrl" => c.is_control(),
+        "print" => !c.is_control(),
+        "graph" => !c.is_control() && !c.is_whitespace(),
+        _ => false,
+    }
+}
+
+// Parse `[...]` starting at `start`, returning the token and the index just past `]`
+fn parse_class(chars: &[char], start: usize) -> Option<(Token, usize)> {
+    let mut i = start + 1;
+    let negated = matches!(chars.get(i), Some('!' | '^'));
+    if negated {
+        i += 1;
+    }
+    let mut items = Vec::new();
+    let mut first = true;
+    loop {
+        let c = *chars.get(i)?;
+        if c == ']' && !first {
+            return Some((Token::Class { negated, items }, i + 1));
+        }
+        first = false;
+
+        // [:name:] character classes
+        if c == '[' && chars.get(i + 1) == Some(&':') {
+            let rest: String = chars[i + 2..].iter().collect();
+            if let Some(end) = rest.find(":]") {
+                items.push(ClassItem::Named(rest[..end].to_string()));
+                i += 2 + rest[..end].chars().count() + 2;
+                continue;
+            }
+        }
+
+        let low = if c == '\\' && i + 1 < chars.len() {
+            i += 1;
+            chars[i]
+        } else {
+            c
+        };
+        if chars.get(i + 1) == Some(&'-') && chars.get(i + 2).is_some_and(|high| *high != ']') {
+            items.push(ClassItem::Range(low, chars[i + 2]));
+            i += 3;
+        } else {
+            items.push(ClassItem::Char(low));
+            i += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negated_class() {
+        let pattern = Pattern::new("[!a-c]x");
+        assert!(pattern.matches("dx"));
+        assert!(!pattern.matches("bx"));
+        assert!(Pattern::new("[^0-9]").matches("z"));
+        assert!(Pattern::new("[!]]").matches("a"));
+        assert!(!Pattern::new("[!]]").matches("]"));
+    }
+
+    #[test]
+    fn star_backtracks() {
+        assert!(Pattern::new("*a*b").matches("xaxaxb"));
+        assert!(Pattern::new("a*b*c").matches("abbbc"));
+        assert!(!Pattern::new("*a*b").matches("xaxax"));
+        assert_eq!(Pattern::new(".*").match_suffix("a.b.c", false), Some(3));
+        assert_eq!(Pattern::new(".*").match_suffix("a.b.c", true), Some(1));
+        assert_eq!(Pattern::new("*.").match_prefix("a.b.c", false), Some(2));
+    }
+
+    #[test]
+    fn classes_and_escapes() {
+        assert!(Pattern::new("[[:digit:]]?").matches("1a"));
+        assert!(!Pattern::new("[[:upper:]]").matches("a"));
+        assert!(Pattern::new("a\\*").matches("a*"));
+        assert!(!Pattern::new("a\\*").matches("ab"));
+        // No closing bracket, so `[` is literal
+        assert!(Pattern::new("[ab").matches("[ab"));
+        assert!(Pattern::new("A?C").ignore_case().matches("abc"));
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(has_wildcards("*.rs"));
+        assert!(has_wildcards("[ab]"));
+        assert!(!has_wildcards("\\*.rs"));
+        assert_eq!(unescape("a\\*b"), "a*b");
+    }
+}
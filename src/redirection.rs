@@ -1,43 +1,207 @@
-pub fn parse_redirection(input: &str) -> (String, Option<(String, bool)>, Option<(String, bool)>) {
-    let mut command_str = input.to_string();
-    let mut stdout_file = None;
-    let mut stderr_file = None;
-
-    // Check for stderr redirection
-    if let Some(redirect_pos) = command_str.find(" 2>> ") {
-        let (cmd_part, file_part) = command_str.split_at(redirect_pos);
-        let file_path = file_part.trim_start_matches(" 2>> ").trim().split_whitespace().next().unwrap_or("");
-        stderr_file = Some((file_path.to_string(), true));
-        command_str = cmd_part.to_string();
-    } else if let Some(redirect_pos) = command_str.find(" 2> ") {
-        let (cmd_part, file_part) = command_str.split_at(redirect_pos);
-        let file_path = file_part.trim_start_matches(" 2> ").trim().split_whitespace().next().unwrap_or("");
-        stderr_file = Some((file_path.to_string(), false));
-        command_str = cmd_part.to_string();
-    }
-
-    // Check for stdout redirection
-    if let Some(redirect_pos) = command_str.find(" 1>> ") {
-        let (cmd_part, file_part) = command_str.split_at(redirect_pos);
-        let file_path = file_part.trim_start_matches(" 1>> ").trim().split_whitespace().next().unwrap_or("");
-        stdout_file = Some((file_path.to_string(), true));
-        command_str = cmd_part.to_string();
-    } else if let Some(redirect_pos) = command_str.find(" >> ") {
-        let (cmd_part, file_part) = command_str.split_at(redirect_pos);
-        let file_path = file_part.trim_start_matches(" >> ").trim().split_whitespace().next().unwrap_or("");
-        stdout_file = Some((file_path.to_string(), true));
-        command_str = cmd_part.to_string();
-    } else if let Some(redirect_pos) = command_str.find(" 1> ") {
-        let (cmd_part, file_part) = command_str.split_at(redirect_pos);
-        let file_path = file_part.trim_start_matches(" 1> ").trim().split_whitespace().next().unwrap_or("");
-        stdout_file = Some((file_path.to_string(), false));
-        command_str = cmd_part.to_string();
-    } else if let Some(redirect_pos) = command_str.find(" > ") {
-        let (cmd_part, file_part) = command_str.split_at(redirect_pos);
-        let file_path = file_part.trim_start_matches(" > ").trim().split_whitespace().next().unwrap_or("");
-        stdout_file = Some((file_path.to_string(), false));
-        command_str = cmd_part.to_string();
-    }
-
-    (command_str, stdout_file, stderr_file)
-}
\ No newline at end of file
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::unix::io::IntoRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::ast::{Redirect, RedirectOp};
+use crate::expansion::{expand_heredoc, expand_word};
+use crate::shell::Shell;
+use crate::utils::{io_error_message, PRIVATE_FD_MIN};
+
+// The fds a command's redirections replaced, put back when this is dropped
+#[derive(Debug, Default)]
+pub struct SavedFds {
+    // (fd, private copy of the original or None if it was closed)
+    saved: Vec<(i32, Option<i32>)>,
+}
+
+impl SavedFds {
+    fn save(&mut self, fd: i32) {
+        if self.saved.iter().any(|(saved_fd, _)| *saved_fd == fd) {
+            return;
+        }
+        let copy = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, PRIVATE_FD_MIN) };
+        self.saved.push((fd, if copy >= 0 { Some(copy) } else { None }));
+    }
+}
+
+impl Drop for SavedFds {
+    fn drop(&mut self) {
+        flush_std_streams();
+        for (fd, copy) in self.saved.drain(..).rev() {
+            unsafe {
+                match copy {
+                    Some(copy) => {
+                        libc::dup2(copy, fd);
+                        libc::close(copy);
+                    }
+                    None => {
+                        libc::close(fd);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Buffered output must land on the fd it was written for
+fn flush_std_streams() {
+    let _ = io::stdout().flush();
+    let _ = io::stderr().flush();
+}
+
+// Apply redirections to the shell's own fds, left to right, so both builtins
+// and spawned children see them. Everything is undone when the result drops.
+pub fn apply_redirections(shell: &mut Shell, redirects: &[Redirect]) -> Result<SavedFds, String> {
+    flush_std_streams();
+    let mut saved = SavedFds::default();
+    for redirect in redirects {
+        apply_redirect(shell, redirect, &mut saved)?;
+    }
+    Ok(saved)
+}
+
+fn apply_redirect(shell: &mut Shell, redirect: &Redirect, saved: &mut SavedFds) -> Result<(), String> {
+    let target = match redirect.op {
+        RedirectOp::HereDoc { expand: true } => expand_heredoc(shell, &redirect.target.0),
+        RedirectOp::HereDoc { expand: false } => Ok(redirect.target.0.clone()),
+        _ => expand_word(shell, &redirect.target),
+    }
+    .map_err(|error| error.into_message(shell))?;
+    let default_fd = match redirect.op {
+        RedirectOp::Input
+        | RedirectOp::ReadWrite
+        | RedirectOp::DupInput
+        | RedirectOp::HereDoc { .. }
+        | RedirectOp::HereString => 0,
+        _ => 1,
+    };
+    let fd = redirect.fd.unwrap_or(default_fd);
+
+    match redirect.op {
+        RedirectOp::Output | RedirectOp::Clobber => {
+            saved.save(fd);
+            install_file(fd, open_output(&target, false)?)
+        }
+        RedirectOp::Append => {
+            saved.save(fd);
+            install_file(fd, open_output(&target, true)?)
+        }
+        RedirectOp::Input => {
+            saved.save(fd);
+            install_file(fd, File::open(&target).map_err(|e| describe(&target, &e))?)
+        }
+        RedirectOp::ReadWrite => {
+            saved.save(fd);
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&target)
+                .map_err(|e| describe(&target, &e))?;
+            install_file(fd, file)
+        }
+        RedirectOp::HereDoc { .. } => {
+            saved.save(fd);
+            install_file(fd, here_document_file(&target).map_err(|e| describe("here-document", &e))?)
+        }
+        RedirectOp::HereString => {
+            saved.save(fd);
+            let body = format!("{}\n", target);
+            install_file(fd, here_document_file(&body).map_err(|e| describe("here-string", &e))?)
+        }
+        RedirectOp::OutputBoth | RedirectOp::AppendBoth => {
+            let append = redirect.op == RedirectOp::AppendBoth;
+            saved.save(1);
+            saved.save(2);
+            install_file(1, open_output(&target, append)?)?;
+            duplicate(1, 2)
+        }
+        RedirectOp::DupOutput | RedirectOp::DupInput => {
+            if target == "-" {
+                saved.save(fd);
+                unsafe {
+                    libc::close(fd);
+                }
+                return Ok(());
+            }
+            match target.parse::<i32>() {
+                Ok(source) => {
+                    saved.save(fd);
+                    duplicate(source, fd)
+                }
+                // `>&file` with no fd is the old spelling of `&>file`
+                Err(_) if redirect.op == RedirectOp::DupOutput && redirect.fd.is_none() => {
+                    saved.save(1);
+                    saved.save(2);
+                    install_file(1, open_output(&target, false)?)?;
+                    duplicate(1, 2)
+                }
+                Err(_) => Err(format!("{}: ambiguous redirect", target)),
+            }
+        }
+    }
+}
+
+fn describe(target: &str, error: &io::Error) -> String {
+    format!("{}: {}", target, io_error_message(error))
+}
+
+// Stage a here-document or here-string body in an unlinked temp file, ready to read from the start
+fn here_document_file(body: &str) -> io::Result<File> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "rsh-heredoc-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::SeqCst)
+    ));
+    let mut file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+    let _ = std::fs::remove_file(&path);
+    file.write_all(body.as_bytes())?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file)
+}
+
+fn open_output(file_path: &str, append: bool) -> Result<File, String> {
+    if let Some(parent) = Path::new(file_path).parent() {
+        let _ = std::fs::create_dir_all(parent);
+    }
+    let file = if append {
+        OpenOptions::new().create(true).append(true).open(file_path)
+    } else {
+        File::create(file_path)
+    };
+    file.map_err(|e| describe(file_path, &e))
+}
+
+// Move an opened file onto `fd`, leaving it inheritable by children
+fn install_file(fd: i32, file: File) -> Result<(), String> {
+    let raw = file.into_raw_fd();
+    unsafe {
+        if raw == fd {
+            libc::fcntl(fd, libc::F_SETFD, 0);
+            return Ok(());
+        }
+        let result = libc::dup2(raw, fd);
+        let error = io::Error::last_os_error();
+        libc::close(raw);
+        if result < 0 {
+            return Err(describe(&fd.to_string(), &error));
+        }
+    }
+    Ok(())
+}
+
+// Make `fd` a copy of `source`, as in `2>&1`
+fn duplicate(source: i32, fd: i32) -> Result<(), String> {
+    unsafe {
+        if libc::fcntl(source, libc::F_GETFD) < 0 {
+            return Err(describe(&source.to_string(), &io::Error::last_os_error()));
+        }
+        if source != fd && libc::dup2(source, fd) < 0 {
+            return Err(describe(&fd.to_string(), &io::Error::last_os_error()));
+        }
+    }
+    Ok(())
+}
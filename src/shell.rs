@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::rc::Rc;
+use rustyline::Editor;
+use rustyline::history::DefaultHistory;
+use crate::ast::Command;
+use crate::completer::MyCompleter;
+use crate::execution::execute_list;
+use crate::glob::GlobOptions;
+use crate::parser::{needs_more_input, parse};
+use crate::signals;
+use crate::variables::Variables;
+
+// Pending jump out of the commands being run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unwind {
+    // `return` from the innermost function
+    Return,
+    // `break n` out of the n innermost loops
+    Break(usize),
+    // `continue n`, going on with the next pass of the nth loop out
+    Continue(usize),
+}
+
+// State shared by the read loop, the executor and the builtins
+pub struct Shell {
+    pub rl: Editor<MyCompleter, DefaultHistory>,
+    // Track the last written history position for -a command
+    pub last_written_pos: usize,
+    // Exit status of the most recent command
+    pub last_status: i32,
+    pub vars: Variables,
+    // Command registered with `trap ... EXIT`
+    pub exit_trap: Option<String>,
+    // Set in forked children, which must not touch history or traps
+    pub is_subshell: bool,
+    // $0 and $1, $2, ...
+    pub script_name: String,
+    pub positional: Vec<String>,
+    // $$ stays the main shell's pid in subshells
+    pub pid: u32,
+    // $!
+    pub last_background_pid: Option<i32>,
+    // Reading commands from a terminal, for $-
+    pub interactive: bool,
+    // Running a script file, which keeps no history
+    pub in_script: bool,
+    pub functions: HashMap<String, Rc<Command>>,
+    // How many function calls are running, `return` is only valid inside one
+    pub function_depth: usize,
+    // Loops running in the current function, for `break` and `continue`
+    pub loop_depth: usize,
+    pub unwind: Option<Unwind>,
+    // Status of the last command substitution, which a command with no name reports
+    pub substitution_status: Option<i32>,
+    pub glob_options: GlobOptions,
+}
+
+impl Shell {
+    pub fn new() -> Self {
+        let mut rl = Editor::<MyCompleter, DefaultHistory>::new().unwrap();
+        rl.set_helper(Some(MyCompleter::new()));
+        Shell {
+            rl,
+            last_written_pos: 0,
+            last_status: 0,
+            vars: Variables::from_env(),
+            exit_trap: None,
+            is_subshell: false,
+            script_name: env::args().next().unwrap_or_default(),
+            positional: Vec::new(),
+            pid: std::process::id(),
+            last_background_pid: None,
+            interactive: signals::is_interactive(),
+            in_script: false,
+            functions: HashMap::new(),
+            function_depth: 0,
+            loop_depth: 0,
+            unwind: None,
+            substitution_status: None,
+            glob_options: GlobOptions::default(),
+        }
+    }
+
+    pub fn history_entries(&self) -> Vec<String> {
+        self.rl.history().iter().map(|s| s.to_string()).collect()
+    }
+
+    pub fn enter_subshell(&mut self) {
+        self.is_subshell = true;
+        self.exit_trap = None;
+    }
+
+    // Every way of leaving the shell ends up here
+    pub fn shutdown(&mut self, status: i32) -> ! {
+        if self.is_subshell {
+            let _ = std::io::stdout().flush();
+            let _ = std::io::stderr().flush();
+            unsafe { libc::_exit(status) }
+        }
+        if let Some(command) = self.exit_trap.take() {
+            match parse(&command) {
+                Ok(list) => {
+                    execute_list(self, &list);
+                }
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+        self.save_history();
+        let _ = std::io::stdout().flush();
+        std::process::exit(status)
+    }
+
+    // Add the commands in a history file. Multi-line commands are saved with
+    // their newlines, so lines are joined again until the command is complete.
+    pub fn read_history(&mut self, contents: &str) {
+        let mut pending: Vec<&str> = Vec::new();
+        for line in contents.lines() {
+            if pending.is_empty() && line.trim().is_empty() {
+                continue;
+            }
+            pending.push(line);
+            let command = pending.join("\n");
+            if !needs_more_input(&command) {
+                let _ = self.rl.add_history_entry(command.trim());
+                pending.clear();
+            }
+        }
+        // Whatever never finished goes in line by line, as it was written
+        for line in pending {
+            let trimmed = line.trim();
+            if !trimmed.is_empty() {
+                let _ = self.rl.add_history_entry(trimmed);
+            }
+        }
+    }
+
+    // Write this session's commands to HISTFILE
+    pub fn save_history(&self) {
+        if self.in_script {
+            return;
+        }
+        if let Ok(histfile_path) = env::var("HISTFILE") {
+            let history = self.history_entries();
+
+            // Check if file already exists and has content
+            let existing_content = std::fs::read_to_string(&histfile_path).unwrap_or_default();
+
+            if existing_content.lines().next().is_none() {
+                // No existing history or we didn't load any - write all history
+                let _ = std::fs::write(&histfile_path, history.join("\n") + "\n");
+            } else if self.last_written_pos < history.len() {
+                // Append only new commands from current session
+                if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(&histfile_path) {
+                    for command in &history[self.last_written_pos..] {
+                        let _ = writeln!(file, "{}", command);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multi_line_history_entries_survive_a_reload() {
+        let mut shell = Shell::new();
+        shell.read_history("echo one\necho 'open\nclose'\n\nfor i in 1 2; do\necho $i\ndone\n  echo two  \necho 'never closed\nls\n");
+        assert_eq!(
+            shell.history_entries(),
+            [
+                "echo one",
+                "echo 'open\nclose'",
+                "for i in 1 2; do\necho $i\ndone",
+                "echo two",
+                "echo 'never closed",
+                "ls",
+            ]
+        );
+    }
+}
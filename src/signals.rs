@@ -0,0 +1,144 @@
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use crate::utils::PRIVATE_FD_MIN;
+
+// Terminal and process group bookkeeping for an interactive shell.
+// Foreground children run in their own process group which owns the
+// terminal, so Ctrl-C reaches them and not the shell.
+
+static INTERACTIVE: AtomicBool = AtomicBool::new(false);
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static TTY_FD: AtomicI32 = AtomicI32::new(-1);
+static SHELL_PGID: AtomicI32 = AtomicI32::new(0);
+
+// Signals the shell handles or ignores but its children must get back to default.
+// SIGTSTP, SIGTTIN and SIGTTOU stay ignored: without job control nothing could
+// resume a stopped child, and waiting on it would hang the shell.
+const CHILD_SIGNALS: [libc::c_int; 2] = [libc::SIGINT, libc::SIGQUIT];
+
+extern "C" fn on_sigint(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+pub fn init() {
+    unsafe {
+        if libc::isatty(0) != 1 {
+            return;
+        }
+        // Keep a private handle on the terminal that survives redirections
+        let tty_fd = libc::fcntl(0, libc::F_DUPFD_CLOEXEC, PRIVATE_FD_MIN);
+        if tty_fd < 0 {
+            return;
+        }
+
+        libc::signal(libc::SIGQUIT, libc::SIG_IGN);
+        libc::signal(libc::SIGTSTP, libc::SIG_IGN);
+        libc::signal(libc::SIGTTIN, libc::SIG_IGN);
+        libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+
+        // A handler rather than SIG_IGN, so exec'd children reset it to default
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_sigint as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut());
+
+        // Become the foreground process group of the terminal
+        let pid = libc::getpid();
+        if libc::getpgrp() != pid {
+            libc::setpgid(0, 0);
+        }
+        libc::tcsetpgrp(tty_fd, pid);
+
+        TTY_FD.store(tty_fd, Ordering::SeqCst);
+        SHELL_PGID.store(pid, Ordering::SeqCst);
+        INTERACTIVE.store(true, Ordering::SeqCst);
+    }
+}
+
+pub fn is_interactive() -> bool {
+    INTERACTIVE.load(Ordering::SeqCst)
+}
+
+// Set when Ctrl-C hit the shell itself or killed a foreground child
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+pub fn set_interrupted() {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+pub fn clear_interrupted() {
+    INTERRUPTED.store(false, Ordering::SeqCst);
+}
+
+// Start `command` in the foreground job `pgid` (0 starts a new job led by the child)
+pub fn prepare_foreground(command: &mut Command, pgid: i32) {
+    if !is_interactive() {
+        return;
+    }
+    let tty_fd = TTY_FD.load(Ordering::SeqCst);
+    command.process_group(pgid);
+    unsafe {
+        command.pre_exec(move || {
+            // Grab the terminal before exec so the child never reads it from the background
+            libc::tcsetpgrp(tty_fd, libc::getpgrp());
+            for signal in CHILD_SIGNALS {
+                libc::signal(signal, libc::SIG_DFL);
+            }
+            Ok(())
+        });
+    }
+}
+
+// Runs in a forked subshell: join job `pgid` and give up the shell's signal setup
+pub fn enter_child(pgid: i32, foreground: bool) {
+    unsafe {
+        if is_interactive() {
+            let pgid = if pgid == 0 { libc::getpid() } else { pgid };
+            libc::setpgid(0, pgid);
+            if foreground {
+                libc::tcsetpgrp(TTY_FD.load(Ordering::SeqCst), pgid);
+            }
+            // Commands it starts stay in this job
+            INTERACTIVE.store(false, Ordering::SeqCst);
+        }
+        for signal in CHILD_SIGNALS {
+            libc::signal(signal, libc::SIG_DFL);
+        }
+        libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+    }
+}
+
+// Parent side of prepare_foreground, done by both so neither side races
+pub fn foreground_job(pid: u32, pgid: i32) {
+    if !is_interactive() {
+        return;
+    }
+    let pgid = if pgid == 0 { pid as i32 } else { pgid };
+    unsafe {
+        libc::setpgid(pid as i32, pgid);
+        libc::tcsetpgrp(TTY_FD.load(Ordering::SeqCst), pgid);
+    }
+}
+
+// Parent side of a background job: its own process group, but no terminal
+pub fn background_job(pid: u32) {
+    if !is_interactive() {
+        return;
+    }
+    unsafe {
+        libc::setpgid(pid as i32, pid as i32);
+    }
+}
+
+// Take the terminal back once the foreground job is done
+pub fn reclaim_terminal() {
+    if !is_interactive() {
+        return;
+    }
+    unsafe {
+        libc::tcsetpgrp(TTY_FD.load(Ordering::SeqCst), SHELL_PGID.load(Ordering::SeqCst));
+    }
+}
@@ -1,6 +1,8 @@
 use std::path::Path;
-use std::fs::{File, OpenOptions};
-use std::io::Write;
+use std::io;
+
+// Lowest fd the shell uses for its own bookkeeping, above what scripts touch
+pub const PRIVATE_FD_MIN: i32 = 64;
 
 pub fn is_executable(path: &Path) -> bool {
     use std::os::unix::fs::PermissionsExt;
@@ -9,29 +11,24 @@ pub fn is_executable(path: &Path) -> bool {
         .unwrap_or(false)
 }
 
-pub fn write_to_file(file_path: &str, content: &str, append: bool) {
-    if let Some(parent) = Path::new(file_path).parent() {
-        let _ = std::fs::create_dir_all(parent);
-    }
-    let result = if append {
-        OpenOptions::new().create(true).append(true).open(file_path)
-    } else {
-        File::create(file_path)
-    };
-    match result {
-        Ok(mut file) => {
-            let _ = writeln!(file, "{}", content);
-        }
-        Err(e) => {
-            println!("Error writing to file {}: {}", file_path, e);
-        }
+// io::Error text without the trailing "(os error N)"
+pub fn io_error_message(error: &io::Error) -> String {
+    let message = error.to_string();
+    match message.find(" (os error") {
+        Some(end) => message[..end].to_string(),
+        None => message,
     }
 }
 
-pub fn print_or_write(output: Option<&(String, bool)>, message: &str) {
-    if let Some((file_path, append)) = output {
-        write_to_file(file_path, message, *append);
+// Quote a value so the shell would read it back unchanged
+pub fn shell_quote(value: &str) -> String {
+    let safe = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c));
+    if safe {
+        value.to_string()
     } else {
-        println!("{}", message);
+        format!("'{}'", value.replace('\'', "'\\''"))
     }
-}
\ No newline at end of file
+}
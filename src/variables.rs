@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::env;
+
+#[derive(Debug, Clone, Default)]
+pub struct Variable {
+    // None for a name that was exported or made readonly before being given a value
+    pub value: Option<String>,
+    // Exported variables are passed to child processes
+    pub exported: bool,
+    pub readonly: bool,
+}
+
+// Shell variables, both shell-local and exported
+#[derive(Debug, Default)]
+pub struct Variables {
+    vars: HashMap<String, Variable>,
+}
+
+impl Variables {
+    // Everything inherited from the environment starts out exported
+    pub fn from_env() -> Self {
+        let mut variables = Variables::default();
+        for (name, value) in env::vars() {
+            variables.vars.insert(
+                name,
+                Variable {
+                    value: Some(value),
+                    exported: true,
+                    readonly: false,
+                },
+            );
+        }
+        variables
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).and_then(|var| var.value.as_deref())
+    }
+
+    // Name/value pairs for a child process environment
+    pub fn exported(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.vars.iter().filter(|(_, var)| var.exported).filter_map(|(name, var)| {
+            var.value.as_deref().map(|value| (name.as_str(), value))
+        })
+    }
+
+    // Every variable sorted by name, for the listing builtins
+    pub fn sorted(&self) -> Vec<(&str, &Variable)> {
+        let mut vars: Vec<(&str, &Variable)> =
+            self.vars.iter().map(|(name, var)| (name.as_str(), var)).collect();
+        vars.sort_by(|a, b| a.0.cmp(b.0));
+        vars
+    }
+
+    pub fn is_readonly(&self, name: &str) -> bool {
+        self.vars.get(name).is_some_and(|var| var.readonly)
+    }
+
+    // Assign a value, keeping the export flag of an existing variable
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        if self.is_readonly(name) {
+            return Err(format!("{}: readonly variable", name));
+        }
+        self.vars.entry(name.to_string()).or_default().value = Some(value.to_string());
+        Ok(())
+    }
+
+    pub fn set_exported(&mut self, name: &str, exported: bool) {
+        match self.vars.get_mut(name) {
+            Some(var) => var.exported = exported,
+            None if exported => {
+                self.vars.insert(
+                    name.to_string(),
+                    Variable {
+                        exported,
+                        ..Variable::default()
+                    },
+                );
+            }
+            None => {}
+        }
+    }
+
+    pub fn set_readonly(&mut self, name: &str) {
+        self.vars.entry(name.to_string()).or_default().readonly = true;
+    }
+
+    pub fn unset(&mut self, name: &str) -> Result<(), String> {
+        if self.is_readonly(name) {
+            return Err(format!("{}: cannot unset: readonly variable", name));
+        }
+        self.vars.remove(name);
+        Ok(())
+    }
+}
+
+// NAME must start with a letter or underscore, then letters, digits or underscores
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Split `NAME=value` into its parts if the word is an assignment
+pub fn split_assignment(word: &str) -> Option<(&str, &str)> {
+    let (name, value) = word.split_once('=')?;
+    if is_valid_name(name) {
+        Some((name, value))
+    } else {
+        None
+    }
+}